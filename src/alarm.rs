@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write as _};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::callsite::Identifier;
+use tracing::Level;
+use tracing::Metadata;
+
+use crate::formatter::{LogFmt, EVENT_UUID};
+#[cfg(feature = "json")]
+use crate::network::NetworkSink;
+use crate::subscriber::{EventTagSet, TreeEvent};
+use crate::timings::Rfc3339;
+
+// Alarms are handed to every handler as soon as they're raised, not when
+// the tree they belong to is done.
+
+#[derive(Clone, Debug)]
+pub struct Alarm<E> {
+    // Of the tree the alarm was raised in, which is logged after the alarm
+    pub uuid: Option<String>,
+    pub timestamp: SystemTime,
+    pub level: Level,
+    pub message: String,
+    pub tag: Option<E>,
+    pub values: Vec<(&'static str, String)>,
+    // Names of the spans the alarm was raised in, root first
+    pub span_path: Vec<&'static str>,
+    // The rule that raised the alarm, unless it was `alarm!`
+    pub escalation: Option<String>,
+}
+
+// Rules that raise alarms on their own, see
+// `TreeLayer::with_escalation`.
+#[derive(Clone, Copy, Debug)]
+pub enum Escalation<E> {
+    // Any event with this tag
+    Tag(E),
+    // More than this many events with this tag in one tree
+    TagCount(E, usize),
+    // A root span open for longer than this, from creation to close
+    SlowRoot(Duration),
+}
+
+// Implemented for closures taking an `&Alarm<E>`, e.g. to page someone.
+pub trait AlarmHandler<E>: 'static + Send + Sync {
+    fn alarm(&self, alarm: &Alarm<E>) -> io::Result<()>;
+}
+
+// Caps how many alarms reach the handlers, see
+// `TreeLayer::with_alarm_limit`. The burst is per callsite, whatever the
+// message; a single message is only limited by `with_dedup`. Suppressed
+// alarms are counted, and reported in a summary alarm naming the callsite
+// once the window is over: with the next alarm or root span to close, or
+// when the subscriber is dropped.
+#[derive(Clone, Copy, Debug)]
+pub struct AlarmLimit {
+    burst: u32,
+    window: Duration,
+    dedup: Duration,
+}
+
+pub(crate) struct AlarmLimiter<E> {
+    limit: AlarmLimit,
+    callsites: Mutex<HashMap<Identifier, CallsiteAlarms<E>>>,
+}
+
+struct CallsiteAlarms<E> {
+    // Where the alarms come from, e.g. `src/main.rs:12`, to name in the
+    // summary
+    location: String,
+    window_start: Instant,
+    sent: u32,
+    suppressed: u64,
+    // The latest alarm suppressed in this window, which the summary is
+    // raised as, reworded for the whole callsite
+    last_suppressed: Option<Alarm<E>>,
+    last_sent: HashMap<String, Instant>,
+}
+
+pub(crate) struct Verdict<E> {
+    // For the windows that just ended with alarms suppressed, from any
+    // callsite
+    pub summaries: Vec<Alarm<E>>,
+    pub send: bool,
+}
+
+// The default handler, in the subscriber's format.
+#[derive(Clone, Copy, Debug)]
+pub struct StderrAlarms {
+    fmt: LogFmt,
+    ascii: bool,
+}
+
+// Appends a line per alarm, for something else to watch.
+#[derive(Clone, Debug)]
+pub struct FileAlarms {
+    path: String,
+    fmt: LogFmt,
+    ascii: bool,
+}
+
+// Streams a line per alarm to a log shipper, separately from the trees.
+#[cfg(feature = "json")]
+pub struct NetworkAlarms {
+    sink: Mutex<NetworkSink>,
+    fmt: LogFmt,
+    ascii: bool,
+}
+
+impl<E: EventTagSet> Alarm<E> {
+    pub(crate) fn new(
+        event: &TreeEvent<E>,
+        uuid: Option<String>,
+        span_path: Vec<&'static str>,
+    ) -> Self {
+        Alarm {
+            uuid,
+            timestamp: event.timestamp,
+            level: event.level,
+            message: event.message.clone(),
+            tag: event.tag,
+            values: event.values.clone(),
+            span_path,
+            escalation: None,
+        }
+    }
+
+    // A single line, without the newline.
+    pub fn format(&self, fmt: LogFmt) -> String {
+        self.format_line(fmt, false)
+    }
+
+    // With `ascii`, like `PrettyConfig::with_ascii`.
+    pub(crate) fn format_line(&self, fmt: LogFmt, ascii: bool) -> String {
+        match fmt {
+            #[cfg(feature = "json")]
+            LogFmt::Json => self.format_json(),
+            LogFmt::Pretty => self.format_pretty(ascii),
+        }
+    }
+
+    // `uuid 2021-07-20T18:23:51+00:00 🚨 [ALARM]🔹root🔹child: message | key=value`,
+    // or `... [ALARM] root > child: ...` in ASCII.
+    fn format_pretty(&self, ascii: bool) -> String {
+        let uuid = self.uuid.as_deref().unwrap_or(EVENT_UUID);
+        let mut writer = format!("{} {} ", uuid, Rfc3339(self.timestamp));
+
+        if ascii {
+            writer.push_str("[ALARM]");
+            for (i, name) in self.span_path.iter().enumerate() {
+                let separator = if i == 0 { " " } else { " > " };
+                write!(writer, "{}{}", separator, name).expect("Write failed");
+            }
+        } else {
+            writer.push_str("🚨 [ALARM]");
+            for name in self.span_path.iter() {
+                write!(writer, "🔹{}", name).expect("Write failed");
+            }
+        }
+
+        write!(writer, ": {}", self.message).expect("Write failed");
+
+        for (key, value) in self.values.iter() {
+            write!(writer, " | {}={}", key, value).expect("Write failed");
+        }
+
+        if let Some(ref escalation) = self.escalation {
+            write!(writer, " | escalation={}", escalation).expect("Write failed");
+        }
+
+        writer
+    }
+
+    // Shaped like the events of `LogFmt::Json` trees.
+    #[cfg(feature = "json")]
+    fn format_json(&self) -> String {
+        let values = self
+            .values
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.as_str().into()))
+            .collect::<serde_json::Map<_, _>>();
+
+        serde_json::json!({
+            "uuid": self.uuid.as_deref().unwrap_or(EVENT_UUID),
+            "timestamp": Rfc3339(self.timestamp).to_string(),
+            "level": self.level.as_str(),
+            "message": self.message,
+            "log-type": "alarm",
+            "tag": self.tag.map(EventTagSet::pretty),
+            "spans": self.span_path,
+            "values": values,
+            "escalation": self.escalation,
+        })
+        .to_string()
+    }
+}
+
+impl<E: EventTagSet> Escalation<E> {
+    // Describes the rule if `tag` triggers it, with `count` being the number
+    // of events with that tag in the tree so far.
+    pub(crate) fn triggered_by(&self, tag: E, count: usize) -> Option<String> {
+        match *self {
+            Escalation::Tag(rule) if rule.event_tag() == tag.event_tag() => {
+                Some(format!("any {} event", tag.pretty()))
+            }
+            // Only once per tree
+            Escalation::TagCount(rule, limit)
+                if rule.event_tag() == tag.event_tag() && count == limit + 1 =>
+            {
+                Some(format!("more than {} {} events", limit, tag.pretty()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl AlarmLimit {
+    // At most `burst` alarms per callsite in each `window`.
+    pub fn new(burst: u32, window: Duration) -> Self {
+        AlarmLimit {
+            burst,
+            window,
+            dedup: Duration::from_secs(0),
+        }
+    }
+
+    // Also drop alarms repeating the message of one from the same callsite
+    // that was sent less than `dedup` ago.
+    pub fn with_dedup(mut self, dedup: Duration) -> Self {
+        self.dedup = dedup;
+        self
+    }
+}
+
+impl<E> AlarmLimiter<E> {
+    pub(crate) fn new(limit: AlarmLimit) -> Self {
+        AlarmLimiter {
+            limit,
+            callsites: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn check(&self, callsite: &'static Metadata<'static>, alarm: &Alarm<E>) -> Verdict<E>
+    where
+        E: Clone,
+    {
+        let now = Instant::now();
+        let AlarmLimit { burst, dedup, .. } = self.limit;
+
+        let mut callsites = self.callsites.lock().expect("Alarm limiter poisoned");
+        let summaries = self.roll(&mut callsites, now, false);
+
+        let alarms = callsites
+            .entry(callsite.callsite())
+            .or_insert_with(|| CallsiteAlarms {
+                location: match (callsite.file(), callsite.line()) {
+                    (Some(file), Some(line)) => format!("{}:{}", file, line),
+                    _ => callsite.target().to_string(),
+                },
+                window_start: now,
+                sent: 0,
+                suppressed: 0,
+                last_suppressed: None,
+                last_sent: HashMap::new(),
+            });
+
+        let duplicate = matches!(
+            alarms.last_sent.get(&alarm.message),
+            Some(sent_at) if now.duration_since(*sent_at) < dedup
+        );
+
+        if duplicate || alarms.sent >= burst {
+            alarms.suppressed += 1;
+            alarms.last_suppressed = Some(alarm.clone());
+            return Verdict {
+                summaries,
+                send: false,
+            };
+        }
+
+        alarms.sent += 1;
+        alarms.last_sent.insert(alarm.message.clone(), now);
+        Verdict {
+            summaries,
+            send: true,
+        }
+    }
+
+    // Summaries for the windows that are over, or for every window with
+    // suppressed alarms when `all`, e.g. when the subscriber is dropped.
+    pub(crate) fn flush(&self, all: bool) -> Vec<Alarm<E>> {
+        let mut callsites = self.callsites.lock().expect("Alarm limiter poisoned");
+        self.roll(&mut callsites, Instant::now(), all)
+    }
+
+    // Starts a new window for each callsite whose window is over, with a
+    // summary of what the old one suppressed.
+    fn roll(
+        &self,
+        callsites: &mut HashMap<Identifier, CallsiteAlarms<E>>,
+        now: Instant,
+        all: bool,
+    ) -> Vec<Alarm<E>> {
+        let AlarmLimit { window, dedup, .. } = self.limit;
+        let mut summaries = vec![];
+
+        for alarms in callsites.values_mut() {
+            let elapsed = now.duration_since(alarms.window_start);
+            if elapsed < window && !all {
+                continue;
+            }
+
+            if let Some(mut summary) = alarms.last_suppressed.take() {
+                summary.message = format!(
+                    "{}: {} alarms suppressed in last {}ms",
+                    alarms.location,
+                    alarms.suppressed,
+                    elapsed.as_millis()
+                );
+                summary.timestamp = SystemTime::now();
+                summary.values = vec![];
+                summaries.push(summary);
+            }
+
+            alarms.window_start = now;
+            alarms.sent = 0;
+            alarms.suppressed = 0;
+            alarms
+                .last_sent
+                .retain(|_, sent_at| now.duration_since(*sent_at) < dedup);
+        }
+
+        summaries
+    }
+}
+
+impl<E, F> AlarmHandler<E> for F
+where
+    F: Fn(&Alarm<E>) + 'static + Send + Sync,
+{
+    fn alarm(&self, alarm: &Alarm<E>) -> io::Result<()> {
+        self(alarm);
+        Ok(())
+    }
+}
+
+impl StderrAlarms {
+    pub fn new(fmt: LogFmt) -> Self {
+        StderrAlarms { fmt, ascii: false }
+    }
+
+    // Only ASCII in the Pretty lines, see `PrettyConfig::with_ascii`.
+    pub fn with_ascii(mut self, ascii: bool) -> Self {
+        self.ascii = ascii;
+        self
+    }
+}
+
+impl<E: EventTagSet> AlarmHandler<E> for StderrAlarms {
+    fn alarm(&self, alarm: &Alarm<E>) -> io::Result<()> {
+        writeln!(io::stderr(), "{}", alarm.format_line(self.fmt, self.ascii))
+    }
+}
+
+impl FileAlarms {
+    pub fn new(path: impl Into<String>) -> Self {
+        FileAlarms {
+            path: path.into(),
+            fmt: LogFmt::Pretty,
+            ascii: false,
+        }
+    }
+
+    pub fn with_fmt(mut self, fmt: LogFmt) -> Self {
+        self.fmt = fmt;
+        self
+    }
+
+    pub fn with_ascii(mut self, ascii: bool) -> Self {
+        self.ascii = ascii;
+        self
+    }
+}
+
+impl<E: EventTagSet> AlarmHandler<E> for FileAlarms {
+    fn alarm(&self, alarm: &Alarm<E>) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", alarm.format_line(self.fmt, self.ascii))
+    }
+}
+
+#[cfg(feature = "json")]
+impl NetworkAlarms {
+    pub fn new(sink: NetworkSink) -> Self {
+        NetworkAlarms {
+            sink: Mutex::new(sink),
+            fmt: LogFmt::Json,
+            ascii: false,
+        }
+    }
+
+    pub fn with_fmt(mut self, fmt: LogFmt) -> Self {
+        self.fmt = fmt;
+        self
+    }
+
+    pub fn with_ascii(mut self, ascii: bool) -> Self {
+        self.ascii = ascii;
+        self
+    }
+}
+
+#[cfg(feature = "json")]
+impl<E: EventTagSet> AlarmHandler<E> for NetworkAlarms {
+    fn alarm(&self, alarm: &Alarm<E>) -> io::Result<()> {
+        let mut line = alarm.format_line(self.fmt, self.ascii).into_bytes();
+        line.push(b'\n');
+        self.sink
+            .lock()
+            .map_err(|_| io::Error::other("Alarm sink poisoned"))?
+            .send_line(line)
+    }
+}
+
+#[cfg(all(test, feature = "kanidm-tags"))]
+mod tests {
+    use super::{Alarm, AlarmLimit, Escalation, FileAlarms};
+    use crate::formatter::LogFmt;
+    use crate::kanidm::KanidmEventTag;
+    use crate::subscriber::{self, Diagnostic, SpanConfig, TreeLayer, TreeProcessor};
+    #[cfg(feature = "json")]
+    use crate::tests::json_lines;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing::level_filters::LevelFilter;
+    use tracing::trace_span;
+
+    #[test]
+    fn alarm_handlers() {
+        let path = std::env::temp_dir().join(format!("alarm-handlers-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let missing = std::env::temp_dir().join(format!("missing-{}", std::process::id()));
+        let missing = missing.join("alarms.log");
+
+        let alarms = Arc::new(Mutex::new(vec![]));
+        let handler_alarms = alarms.clone();
+
+        let (log_tx, _log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let (diag_tx, diag_rx) = std::sync::mpsc::channel();
+        let subscriber = TreeLayer::pretty(log_tx)
+            .with_diagnostics(diag_tx)
+            .with_alarm_handler(move |alarm: &Alarm<KanidmEventTag>| {
+                handler_alarms.lock().unwrap().push(alarm.clone());
+            })
+            .with_alarm_handler(FileAlarms::new(path))
+            .with_alarm_handler(FileAlarms::new(missing.to_str().unwrap()))
+            .build();
+
+        tracing::subscriber::with_default(subscriber, || {
+            trace_span!("outer").in_scope(|| {
+                trace_span!("inner").in_scope(|| {
+                    alarm!(disk = "sda", "Disk is on fire");
+                });
+            });
+        });
+
+        let alarms = alarms.lock().unwrap();
+        assert_eq!(alarms.len(), 1);
+        assert_eq!(alarms[0].message, "Disk is on fire");
+        assert_eq!(alarms[0].span_path, ["outer", "inner"]);
+        assert_eq!(alarms[0].values, [("disk", "\"sda\"".to_string())]);
+
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(written, format!("{}\n", alarms[0].format(LogFmt::Pretty)));
+        assert!(written.contains("🔹outer🔹inner: Disk is on fire | disk=\"sda\""));
+
+        let ascii = alarms[0].format_line(LogFmt::Pretty, true);
+        assert!(ascii.is_ascii());
+        assert!(ascii.ends_with(" [ALARM] outer > inner: Disk is on fire | disk=\"sda\""));
+
+        // The failing handler didn't stop the others
+        match diag_rx.try_recv() {
+            Ok(Diagnostic::AlarmHandlerFailed { message, .. }) => {
+                assert_eq!(message, "Disk is on fire")
+            }
+            other => panic!("Expected a failed alarm handler, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn alarm_limit() {
+        let alarms = Arc::new(Mutex::new(vec![]));
+        let handler_alarms = alarms.clone();
+
+        let (log_tx, _log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let subscriber = TreeLayer::pretty(log_tx)
+            .with_alarm_handler(move |alarm: &Alarm<KanidmEventTag>| {
+                handler_alarms.lock().unwrap().push(alarm.message.clone());
+            })
+            .with_alarm_limit(
+                AlarmLimit::new(3, Duration::from_millis(200)).with_dedup(Duration::from_secs(5)),
+            )
+            .build();
+
+        fn loop_alarm(i: u32) {
+            alarm!("Loop alarm {}", i);
+        }
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..10 {
+                loop_alarm(i);
+            }
+            // Under the burst size, but deduplicated
+            for _ in 0..3 {
+                alarm!("Repeated alarm");
+            }
+            std::thread::sleep(Duration::from_millis(250));
+            // Closing a root span is enough to report the window
+            trace_span!("after_window").in_scope(|| {});
+            for _ in 0..3 {
+                alarm!("Repeated alarm");
+            }
+        });
+
+        let alarms = alarms.lock().unwrap();
+        assert_eq!(
+            alarms[..4],
+            [
+                "Loop alarm 0",
+                "Loop alarm 1",
+                "Loop alarm 2",
+                "Repeated alarm",
+            ]
+        );
+        // Every callsite's window is reported, with the time it actually lasted
+        // and named by its location, as the suppressed messages may differ
+        let summary = |counted: &str| {
+            alarms[4..6]
+                .iter()
+                .find_map(|alarm| alarm.split_once(counted))
+                .map(|(location, elapsed)| {
+                    assert!(location.starts_with("src/alarm.rs:"));
+                    elapsed.trim_end_matches("ms").parse::<u64>().unwrap()
+                })
+        };
+        assert!(summary(": 7 alarms suppressed in last ").unwrap() >= 250);
+        assert!(summary(": 2 alarms suppressed in last ").unwrap() >= 250);
+        // Pending summaries are sent when the subscriber is dropped
+        assert_eq!(alarms.len(), 8);
+        assert_eq!(alarms[6], "Repeated alarm");
+        assert!(alarms[7].contains(": 2 alarms suppressed in last "));
+    }
+
+    #[test]
+    fn alarm_escalation() {
+        let alarms = Arc::new(Mutex::new(vec![]));
+        let handler_alarms = alarms.clone();
+
+        let (log_tx, _log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let subscriber = TreeLayer::pretty(log_tx)
+            .with_alarm_handler(move |alarm: &Alarm<KanidmEventTag>| {
+                handler_alarms.lock().unwrap().push(alarm.clone());
+            })
+            .with_escalation(Escalation::Tag(KanidmEventTag::SecurityCritical))
+            .with_escalation(Escalation::TagCount(KanidmEventTag::FilterError, 2))
+            .with_escalation(Escalation::Tag(KanidmEventTag::FilterInfo))
+            .with_escalation(Escalation::SlowRoot(Duration::from_millis(50)))
+            .build();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let first = trace_span!("first");
+            // Slow while not entered, it's open time that counts
+            std::thread::sleep(Duration::from_millis(60));
+            first.in_scope(|| {
+                security_critical!("Someone is in");
+                trace_span!("nested").in_scope(|| {
+                    for _ in 0..4 {
+                        filter_error!("Bad filter");
+                    }
+                });
+            });
+            drop(first);
+            // Events filtered out of the tree don't escalate
+            let filtered = trace_span!("filtered");
+            subscriber::configure_span(
+                &filtered,
+                SpanConfig {
+                    out: None,
+                    max_level: LevelFilter::WARN,
+                },
+            );
+            filtered.in_scope(|| {
+                filter_info!("Filtered");
+                for _ in 0..3 {
+                    trace_span!("nested").in_scope(|| filter_error!("Not filtered"));
+                }
+            });
+            trace_span!("second").in_scope(|| {
+                for _ in 0..3 {
+                    filter_error!("Bad filter");
+                }
+                // Explicit alarms don't need a rule
+                alarm!("Explicit");
+            });
+        });
+
+        let alarms = alarms.lock().unwrap();
+        let summary = alarms
+            .iter()
+            .map(|alarm| (alarm.message.as_str(), alarm.escalation.as_deref()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(summary.len(), 6);
+        assert_eq!(
+            summary[0],
+            ("Someone is in", Some("any security.critical event"))
+        );
+        assert_eq!(
+            summary[1],
+            ("Bad filter", Some("more than 2 filter.error events"))
+        );
+        assert_eq!(alarms[1].span_path, ["first", "nested"]);
+        assert!(summary[2].0.starts_with("first took "));
+        assert_eq!(summary[2].1, Some("root span over 50ms"));
+        assert_eq!(
+            summary[3],
+            ("Not filtered", Some("more than 2 filter.error events"))
+        );
+        assert_eq!(
+            summary[4],
+            ("Bad filter", Some("more than 2 filter.error events"))
+        );
+        assert_eq!(summary[5], ("Explicit", None));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn alarm_json() {
+        let path = std::env::temp_dir().join(format!("alarm-json-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let subscriber = TreeLayer::json(log_tx)
+            .with_alarm_handler(FileAlarms::new(path).with_fmt(LogFmt::Json))
+            .build();
+
+        tracing::subscriber::with_default(subscriber, || {
+            trace_span!("alarmed").in_scope(|| {
+                alarm!(disk = "sda", "Disk is on fire");
+            });
+            alarm!("Outside any tree");
+        });
+
+        let root = json_lines(log_rx.recv().unwrap()).remove(0);
+        let alarms = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(alarms.len(), 2);
+        assert_eq!(alarms[0]["uuid"], root["uuid"]);
+        assert_eq!(alarms[0]["log-type"], "alarm");
+        assert_eq!(alarms[0]["level"], "ERROR");
+        assert_eq!(alarms[0]["message"], "Disk is on fire");
+        assert_eq!(alarms[0]["spans"], serde_json::json!(["alarmed"]));
+        assert_eq!(alarms[0]["values"]["disk"], "\"sda\"");
+        assert_eq!(alarms[1]["uuid"], "00000000-0000-0000-0000-000000000000");
+    }
+}
@@ -1,27 +1,423 @@
-use crate::subscriber::{EventTagSet, TreeEvent, TreeProcessed, TreeSpanProcessed};
+use crate::subscriber::{EventTagSet, TagCategory, TreeProcessed};
+#[cfg(feature = "json")]
+use crate::subscriber::{TreeEvent, TreeSpanProcessed};
+use crate::timings::Rfc3339;
+#[cfg(feature = "json")]
 use serde::{ser::SerializeStruct, Serialize};
 use std::fmt;
 use std::io::{self, Write as _};
+use std::time::{Duration, SystemTime};
 use tracing::Level;
+#[cfg(feature = "json")]
 use tracing_serde::AsSerde;
 
 #[derive(Clone, Copy, Debug)]
 pub enum LogFmt {
+    #[cfg(feature = "json")]
     Json,
     Pretty,
 }
 
-const EVENT_UUID: &str = "00000000-0000-0000-0000-000000000000";
+// Options for `LogFmt::Pretty`, see `TreeLayer::with_pretty_config`.
+// Every column is shown by default.
+#[derive(Clone, Debug)]
+pub struct PrettyConfig {
+    uuid: bool,
+    short_uuid: bool,
+    timestamp: bool,
+    timestamp_fmt: TimestampFmt,
+    level: bool,
+    tree: bool,
+    // Depends on `ascii` unless set
+    glyphs: Option<TreeGlyphs>,
+    emoji: bool,
+    ascii: bool,
+    tag: bool,
+    values: bool,
+    callsite: bool,
+    // Resolved against the environment when set, `None` colors terminals
+    // only, see `ColorMode::resolve`
+    color: Option<bool>,
+    // Span durations past this are red instead of dimmed
+    slow_span: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    // When writing to a terminal, unless `NO_COLOR` is set. `CLICOLOR_FORCE`
+    // set to anything but "0" enables it regardless of the terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampFmt {
+    // `2021-07-20T18:23:51.125+00:00`
+    Rfc3339,
+    // RFC 3339 in the local timezone, with the `local-time` feature
+    #[cfg(feature = "local-time")]
+    Local,
+    // Since the root span started, e.g. `+1.52ms`
+    SinceRoot,
+    // Milliseconds since the root span started, e.g. `1.520`
+    ElapsedMs,
+}
+
+// What the tree is drawn with. All four should have the same width.
+#[derive(Clone, Debug)]
+pub struct TreeGlyphs {
+    void: String,
+    line: String,
+    fork: String,
+    turn: String,
+}
+
+#[derive(Clone, Copy)]
+enum Fill {
+    Void,
+    Line,
+    Fork,
+    Turn,
+}
+
+pub(crate) const EVENT_UUID: &str = "00000000-0000-0000-0000-000000000000";
 
 impl LogFmt {
-    pub(crate) fn format<A: EventTagSet>(self, processed_logs: &TreeProcessed<A>) -> Vec<u8> {
+    // `color` is whether the Pretty output may be colored, see
+    // `PrettyConfig::color`.
+    pub(crate) fn format<A: EventTagSet>(
+        self,
+        processed_logs: &TreeProcessed<A>,
+        pretty: &PrettyConfig,
+        color: bool,
+    ) -> Vec<u8> {
         match self {
+            #[cfg(feature = "json")]
             LogFmt::Json => format_json(processed_logs),
-            LogFmt::Pretty => format_pretty(processed_logs),
+            LogFmt::Pretty => format_pretty(processed_logs, pretty, color),
+        }
+    }
+}
+
+impl PrettyConfig {
+    pub fn new() -> Self {
+        PrettyConfig::default()
+    }
+
+    pub fn with_uuid(mut self, uuid: bool) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
+    // Only the first 8 characters of uuids, which is usually enough to tell
+    // trees apart.
+    pub fn with_short_uuid(mut self, short_uuid: bool) -> Self {
+        self.short_uuid = short_uuid;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: bool) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn with_timestamp_fmt(mut self, timestamp_fmt: TimestampFmt) -> Self {
+        self.timestamp_fmt = timestamp_fmt;
+        self
+    }
+
+    pub fn with_level(mut self, level: bool) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_tree(mut self, tree: bool) -> Self {
+        self.tree = tree;
+        self
+    }
+
+    pub fn with_glyphs(mut self, glyphs: TreeGlyphs) -> Self {
+        self.glyphs = Some(glyphs);
+        self
+    }
+
+    pub fn with_emoji(mut self, emoji: bool) -> Self {
+        self.emoji = emoji;
+        self
+    }
+
+    // Only ASCII in what the formatter adds: emojis become markers like
+    // `ERR`, the tree is drawn with `TreeGlyphs::ascii` unless other glyphs
+    // are set, and `µs` is written `us`. Messages and values are unchanged.
+    pub fn with_ascii(mut self, ascii: bool) -> Self {
+        self.ascii = ascii;
+        self
+    }
+
+    pub(crate) fn ascii(&self) -> bool {
+        self.ascii
+    }
+
+    fn glyph(&self, fill: Fill) -> &str {
+        match (&self.glyphs, self.ascii) {
+            (Some(glyphs), _) => glyphs.get(fill),
+            (None, false) => UNICODE_GLYPHS.get(fill),
+            (None, true) => ASCII_GLYPHS.get(fill),
+        }
+    }
+
+    pub fn with_tag(mut self, tag: bool) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    // Fields of events and spans.
+    pub fn with_values(mut self, values: bool) -> Self {
+        self.values = values;
+        self
+    }
+
+    // Ends event lines with where they were emitted, e.g. `@ src/be.rs:123`.
+    pub fn with_callsite(mut self, callsite: bool) -> Self {
+        self.callsite = callsite;
+        self
+    }
+
+    pub fn with_color(mut self, color: ColorMode) -> Self {
+        self.color = color.resolve();
+        self
+    }
+
+    // Highlights spans that took longer than `slow_span` when colored.
+    pub fn with_slow_span(mut self, slow_span: Duration) -> Self {
+        self.slow_span = Some(slow_span);
+        self
+    }
+
+    // Whether to color output written to a sink, given whether it's a
+    // terminal.
+    pub(crate) fn color(&self, is_terminal: bool) -> bool {
+        self.color.unwrap_or(is_terminal)
+    }
+
+    // The columns before the tree.
+    fn write_prefix(
+        &self,
+        writer: &mut Vec<u8>,
+        uuid: &str,
+        timestamp: SystemTime,
+        root_start: SystemTime,
+        level: Level,
+        color: bool,
+    ) -> io::Result<()> {
+        if self.uuid {
+            let uuid = match uuid.get(..8) {
+                Some(short) if self.short_uuid => short,
+                _ => uuid,
+            };
+            write!(writer, "{} ", uuid)?;
+        }
+
+        if self.timestamp {
+            let since_root = timestamp
+                .duration_since(root_start)
+                .unwrap_or_default()
+                .as_nanos() as f64;
+            match self.timestamp_fmt {
+                TimestampFmt::Rfc3339 => write!(writer, "{} ", Rfc3339(timestamp))?,
+                #[cfg(feature = "local-time")]
+                TimestampFmt::Local => write!(
+                    writer,
+                    "{} ",
+                    chrono::DateTime::<chrono::Local>::from(timestamp).to_rfc3339()
+                )?,
+                TimestampFmt::SinceRoot => {
+                    write!(writer, "+{:<7} ", DurationDisplay(since_root, self.ascii))?
+                }
+                TimestampFmt::ElapsedMs => write!(writer, "{:>10.3} ", since_root / 1e6)?,
+            }
+        }
+
+        if self.level {
+            let code = match level {
+                Level::ERROR => RED,
+                Level::WARN => YELLOW,
+                Level::INFO => GREEN,
+                Level::DEBUG => BLUE,
+                Level::TRACE => MAGENTA,
+            };
+            write!(writer, "{} ", Paint(color, code, format!("{:<8}", level)))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for PrettyConfig {
+    fn default() -> Self {
+        PrettyConfig {
+            uuid: true,
+            short_uuid: false,
+            timestamp: true,
+            timestamp_fmt: TimestampFmt::Rfc3339,
+            level: true,
+            tree: true,
+            glyphs: None,
+            emoji: true,
+            ascii: false,
+            tag: true,
+            values: true,
+            callsite: false,
+            color: ColorMode::Auto.resolve(),
+            slow_span: None,
+        }
+    }
+}
+
+impl TreeGlyphs {
+    // `void` is drawn under finished branches, `line` under ongoing ones,
+    // `fork` before a child with siblings after it and `turn` before the
+    // last child.
+    pub fn new(
+        void: impl Into<String>,
+        line: impl Into<String>,
+        fork: impl Into<String>,
+        turn: impl Into<String>,
+    ) -> Self {
+        TreeGlyphs {
+            void: void.into(),
+            line: line.into(),
+            fork: fork.into(),
+            turn: turn.into(),
+        }
+    }
+
+    pub fn unicode() -> Self {
+        let [void, line, fork, turn] = UNICODE_GLYPHS.0;
+        TreeGlyphs::new(void, line, fork, turn)
+    }
+
+    // For terminals without box-drawing characters.
+    pub fn ascii() -> Self {
+        let [void, line, fork, turn] = ASCII_GLYPHS.0;
+        TreeGlyphs::new(void, line, fork, turn)
+    }
+
+    fn get(&self, fill: Fill) -> &str {
+        match fill {
+            Fill::Void => &self.void,
+            Fill::Line => &self.line,
+            Fill::Fork => &self.fork,
+            Fill::Turn => &self.turn,
         }
     }
 }
 
+// The built-in glyphs, in `Fill` order.
+struct StaticGlyphs([&'static str; 4]);
+
+const UNICODE_GLYPHS: StaticGlyphs = StaticGlyphs(["   ", "│  ", "┝━ ", "┕━ "]);
+const ASCII_GLYPHS: StaticGlyphs = StaticGlyphs(["   ", "|  ", "|- ", "`- "]);
+
+impl StaticGlyphs {
+    fn get(&self, fill: Fill) -> &'static str {
+        self.0[fill as usize]
+    }
+}
+
+impl ColorMode {
+    // Whether to color, or `None` if that depends on the sink being a
+    // terminal. Reads the environment for `Auto`, so it's done once per
+    // `PrettyConfig` rather than per tree.
+    fn resolve(self) -> Option<bool> {
+        let env = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+        match self {
+            ColorMode::Always => Some(true),
+            ColorMode::Never => Some(false),
+            ColorMode::Auto if env("NO_COLOR").is_some() => Some(false),
+            ColorMode::Auto => env("CLICOLOR_FORCE")
+                .filter(|force| force != "0")
+                .map(|_| true),
+        }
+    }
+}
+
+const BOLD: &str = "1";
+const DIM: &str = "2";
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const BLUE: &str = "34";
+const MAGENTA: &str = "35";
+const CYAN: &str = "36";
+
+// SGR `code` around the text, when colored.
+struct Paint<T>(bool, &'static str, T);
+
+impl<T: fmt::Display> fmt::Display for Paint<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Paint(true, code, ref text) => write!(f, "\x1b[{}m{}\x1b[0m", code, text),
+            Paint(false, _, ref text) => text.fmt(f),
+        }
+    }
+}
+
+// Nanoseconds, and whether to only use ASCII units.
+struct DurationDisplay(f64, bool);
+
+// This is straight up stolen from chrono
+impl fmt::Display for DurationDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut t = self.0;
+        let micros = if self.1 { "us" } else { "µs" };
+        for unit in ["ns", micros, "ms", "s"].iter() {
+            let value = if t < 10.0 {
+                format!("{:.2}{}", t, unit)
+            } else if t < 100.0 {
+                format!("{:.1}{}", t, unit)
+            } else if t < 1000.0 {
+                format!("{:.0}{}", t, unit)
+            } else {
+                t /= 1000.0;
+                continue;
+            };
+            // Lets callers pad it
+            return f.pad(&value);
+        }
+        f.pad(&format!("{:.0}s", t * 1000.0))
+    }
+}
+
+#[cfg(feature = "json")]
+struct SerializeValues<'a>(&'a [(&'static str, String)]);
+
+#[cfg(feature = "json")]
+impl<'a> Serialize for SerializeValues<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(self.0.iter().map(|(key, value)| (key, value)))
+    }
+}
+
+// Each error field maps to its chain, the error first
+#[cfg(feature = "json")]
+struct SerializeErrors<'a>(&'a [(&'static str, Vec<String>)]);
+
+#[cfg(feature = "json")]
+impl<'a> Serialize for SerializeErrors<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(self.0.iter().map(|(key, chain)| (key, chain)))
+    }
+}
+
+#[cfg(feature = "json")]
 fn format_json<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
     fn fmt_rec<'a, B: EventTagSet>(
         tree: &TreeProcessed<B>,
@@ -42,14 +438,24 @@ fn format_json<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
                     where
                         S: serde::Serializer,
                     {
-                        let mut model = serializer.serialize_struct("event", 7)?;
+                        let mut model = serializer.serialize_struct("event", 14)?;
                         model.serialize_field("uuid", self.uuid)?;
-                        model.serialize_field("timestamp", &self.event.timestamp.to_rfc3339())?;
+                        model.serialize_field(
+                            "timestamp",
+                            &Rfc3339(self.event.timestamp).to_string(),
+                        )?;
                         model.serialize_field("level", &self.event.level.as_serde())?;
                         model.serialize_field("message", &self.event.message)?;
                         model.serialize_field("log-type", "event")?;
                         model.serialize_field("tag", &self.event.tag.map(EventTagSet::pretty))?;
                         model.serialize_field("spans", self.spans)?;
+                        model.serialize_field("target", &self.event.target)?;
+                        model.serialize_field("module_path", &self.event.module_path)?;
+                        model.serialize_field("file", &self.event.file)?;
+                        model.serialize_field("line", &self.event.line)?;
+                        model.serialize_field("thread", &self.event.thread)?;
+                        model.serialize_field("values", &SerializeValues(&self.event.values))?;
+                        model.serialize_field("errors", &SerializeErrors(&self.event.errors))?;
                         model.end()
                     }
                 }
@@ -74,14 +480,19 @@ fn format_json<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
                     where
                         S: serde::Serializer,
                     {
-                        let mut model = serializer.serialize_struct("event", 7)?;
+                        let mut model = serializer.serialize_struct("event", 9)?;
                         model.serialize_field("uuid", self.uuid)?;
-                        model.serialize_field("timestamp", &self.span.timestamp.to_rfc3339())?;
+                        model.serialize_field(
+                            "timestamp",
+                            &Rfc3339(self.span.timestamp).to_string(),
+                        )?;
                         model.serialize_field("level", "TRACE")?;
                         model.serialize_field("message", &self.span.name)?;
                         model.serialize_field("log-type", "span")?;
                         model.serialize_field("nanos-nested", &self.span.nested_duration)?;
                         model.serialize_field("nanos-total", &self.span.total_duration)?;
+                        model.serialize_field("threads", &self.span.threads)?;
+                        model.serialize_field("values", &SerializeValues(&self.span.values))?;
                         model.end()
                     }
                 }
@@ -110,57 +521,62 @@ fn format_json<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
 
     let mut writer = vec![];
     let mut spans = vec![];
-    fmt_rec(&processed_logs, &mut spans, None, &mut writer).expect("Write failed");
+    fmt_rec(processed_logs, &mut spans, None, &mut writer).expect("Write failed");
     writer
 }
 
-fn format_pretty<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
-    #[derive(Clone, Copy)]
-    enum Fill {
-        Void,
-        Line,
-        Fork,
-        Turn,
-    }
-
-    impl fmt::Display for Fill {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            use Fill::*;
-            f.write_str(match self {
-                Void => "   ",
-                Line => "│  ",
-                Fork => "┝━ ",
-                Turn => "┕━ ",
-            })
+fn format_pretty<A: EventTagSet>(
+    processed_logs: &TreeProcessed<A>,
+    config: &PrettyConfig,
+    color: bool,
+) -> Vec<u8> {
+    fn write_indent(
+        writer: &mut Vec<u8>,
+        config: &PrettyConfig,
+        indent: &[Fill],
+    ) -> io::Result<()> {
+        if config.tree {
+            for fill in indent.iter() {
+                write!(writer, "{}", config.glyph(*fill))?;
+            }
         }
+        Ok(())
     }
 
     fn fmt_rec<B: EventTagSet>(
         tree: &TreeProcessed<B>,
         indent: &mut Vec<Fill>,
         uuid: Option<&str>,
-        root_duration: Option<f64>,
+        // When the root span started, and how long it took
+        root: Option<(SystemTime, f64)>,
+        config: &PrettyConfig,
+        color: bool,
         writer: &mut Vec<u8>,
     ) -> io::Result<()> {
         use Fill::*;
         match tree {
             TreeProcessed::Event(event) => {
                 let uuid = uuid.unwrap_or(EVENT_UUID);
-
-                let timestamp_fmt = event.timestamp.to_rfc3339();
+                let root_start = root.map_or(event.timestamp, |(start, _)| start);
 
                 // level, emoji, tag
 
-                let emoji = event
-                    .tag
-                    .map(B::emoji)
-                    .unwrap_or_else(|| match event.level {
-                        Level::ERROR => "🚨",
-                        Level::WARN => "🚧",
-                        Level::INFO => "💬",
-                        Level::DEBUG => "🐛",
-                        Level::TRACE => "📍",
-                    });
+                let emoji = match event.tag {
+                    Some(tag) if !config.ascii => tag.emoji(),
+                    // Tags only have emojis, so they get their level's marker
+                    _ => match (event.tag.map_or(event.level, B::level), config.ascii) {
+                        (Level::ERROR, false) => "🚨",
+                        (Level::WARN, false) => "🚧",
+                        (Level::INFO, false) => "💬",
+                        (Level::DEBUG, false) => "🐛",
+                        (Level::TRACE, false) => "📍",
+                        (Level::ERROR, true) => "ERR",
+                        (Level::WARN, true) => "WRN",
+                        (Level::INFO, true) => "INF",
+                        (Level::DEBUG, true) => "DBG",
+                        (Level::TRACE, true) => "TRC",
+                    },
+                };
 
                 let tag_fmt = event
                     .tag
@@ -173,19 +589,81 @@ fn format_pretty<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
                         Level::TRACE => "trace",
                     });
 
-                write!(writer, "{} {} {:<8} ", uuid, timestamp_fmt, event.level)?;
+                config.write_prefix(
+                    writer,
+                    uuid,
+                    event.timestamp,
+                    root_start,
+                    event.level,
+                    color,
+                )?;
+                write_indent(writer, config, indent)?;
+
+                if config.emoji {
+                    write!(writer, "{} ", emoji)?;
+                }
+
+                if config.tag {
+                    let code = match event.tag.map(B::category) {
+                        Some(TagCategory::Admin) => Some(MAGENTA),
+                        Some(TagCategory::Request) => Some(CYAN),
+                        Some(TagCategory::Security) => Some(RED),
+                        Some(TagCategory::Filter) => Some(BLUE),
+                        Some(TagCategory::Perf) => Some(YELLOW),
+                        Some(TagCategory::Other) | None => None,
+                    };
+                    match code {
+                        Some(code) => write!(writer, "[{}]: ", Paint(color, code, tag_fmt))?,
+                        None => write!(writer, "[{}]: ", tag_fmt)?,
+                    }
+                }
+
+                write!(writer, "{}", event.message)?;
 
-                for fill in indent.iter() {
-                    write!(writer, "{}", fill)?;
+                if config.values {
+                    for (field, value) in event.values.iter() {
+                        write!(writer, " | {}: {}", field, value)?;
+                    }
                 }
 
-                write!(writer, "{} [{}]: {}", emoji, tag_fmt, event.message)?;
+                if let Some(ref thread) = event.thread {
+                    write!(writer, " | thread: {}", thread)?;
+                }
 
-                for (field, value) in event.values.iter() {
-                    write!(writer, " | {}: {}", field, value)?;
+                if config.callsite {
+                    let callsite = match (&event.file, event.line) {
+                        (Some(file), Some(line)) => format!("@ {}:{}", file, line),
+                        (Some(file), None) => format!("@ {}", file),
+                        _ => format!("@ {}", event.module_path.as_ref().unwrap_or(&event.target)),
+                    };
+                    write!(writer, " {}", Paint(color, DIM, callsite))?;
                 }
 
-                writeln!(writer)
+                writeln!(writer)?;
+
+                // One line per source, lined up under the event
+                let mut cause_indent = indent.clone();
+                match cause_indent.last_mut() {
+                    Some(f @ Fork) => *f = Line,
+                    Some(f @ Turn) => *f = Void,
+                    _ => {}
+                }
+                for (field, chain) in event.errors.iter() {
+                    for cause in chain.iter().skip(1) {
+                        config.write_prefix(
+                            writer,
+                            uuid,
+                            event.timestamp,
+                            root_start,
+                            event.level,
+                            color,
+                        )?;
+                        write_indent(writer, config, &cause_indent)?;
+                        writeln!(writer, "   {} caused by: {}", field, cause)?;
+                    }
+                }
+
+                Ok(())
             }
             TreeProcessed::Span(span) => {
                 let uuid = span
@@ -194,45 +672,35 @@ fn format_pretty<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
                     .or(uuid)
                     .expect("Span has no associated UUID, this is a bug");
 
-                let timestamp_fmt = span.timestamp.to_rfc3339();
-
                 let total_duration = span.total_duration as f64;
 
-                let root_duration = root_duration.unwrap_or(total_duration);
+                let (root_start, root_duration) = root.unwrap_or((span.timestamp, total_duration));
 
                 let total_load = 100.0 * total_duration / root_duration;
 
-                struct DurationDisplay(f64);
-
-                // This is straight up stolen from chrono
-                impl fmt::Display for DurationDisplay {
-                    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                        let mut t = self.0;
-                        for unit in ["ns", "µs", "ms", "s"].iter() {
-                            if t < 10.0 {
-                                return write!(f, "{:.2}{}", t, unit);
-                            } else if t < 100.0 {
-                                return write!(f, "{:.1}{}", t, unit);
-                            } else if t < 1000.0 {
-                                return write!(f, "{:.0}{}", t, unit);
-                            }
-                            t /= 1000.0;
-                        }
-                        write!(f, "{:.0}s", t * 1000.0)
-                    }
-                }
-
-                write!(writer, "{} {} TRACE    ", uuid, timestamp_fmt)?;
-
-                for fill in indent.iter() {
-                    write!(writer, "{}", fill)?;
-                }
+                config.write_prefix(
+                    writer,
+                    uuid,
+                    span.timestamp,
+                    root_start,
+                    Level::TRACE,
+                    color,
+                )?;
+                write_indent(writer, config, indent)?;
 
+                let slow = matches!(
+                    config.slow_span,
+                    Some(slow_span) if span.total_duration as u128 > slow_span.as_nanos()
+                );
                 write!(
                     writer,
                     "{} [ {} | ",
-                    span.name,
-                    DurationDisplay(total_duration)
+                    Paint(color, BOLD, span.name),
+                    Paint(
+                        color,
+                        if slow { RED } else { DIM },
+                        DurationDisplay(total_duration, config.ascii)
+                    )
                 )?;
 
                 if span.nested_duration > 0 {
@@ -241,7 +709,20 @@ fn format_pretty<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
                     write!(writer, "{:.3}% / ", direct_load)?;
                 }
 
-                writeln!(writer, "{:.3}% ]", total_load)?;
+                write!(writer, "{:.3}% ]", total_load)?;
+
+                if config.values {
+                    for (field, value) in span.values.iter() {
+                        write!(writer, " | {}: {}", field, value)?;
+                    }
+                }
+
+                for (i, thread) in span.threads.iter().enumerate() {
+                    let sep = if i == 0 { " | threads: " } else { ", " };
+                    write!(writer, "{}{}", sep, thread)?;
+                }
+
+                writeln!(writer)?;
 
                 if let Some((last, remaining)) = span.processed_buf.split_last() {
                     // This span has children
@@ -259,14 +740,30 @@ fn format_pretty<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
                         if let Some(fill) = indent.last_mut() {
                             *fill = Fork;
                         }
-                        fmt_rec(logs, indent, Some(uuid), Some(root_duration), writer)?;
+                        fmt_rec(
+                            logs,
+                            indent,
+                            Some(uuid),
+                            Some((root_start, root_duration)),
+                            config,
+                            color,
+                            writer,
+                        )?;
                     }
 
                     // Last child, set to Turn
                     if let Some(fill) = indent.last_mut() {
                         *fill = Turn;
                     }
-                    fmt_rec(last, indent, Some(uuid), Some(root_duration), writer)?;
+                    fmt_rec(
+                        last,
+                        indent,
+                        Some(uuid),
+                        Some((root_start, root_duration)),
+                        config,
+                        color,
+                        writer,
+                    )?;
 
                     indent.pop();
                 } else {
@@ -280,6 +777,147 @@ fn format_pretty<A: EventTagSet>(processed_logs: &TreeProcessed<A>) -> Vec<u8> {
 
     let mut writer = vec![];
     let mut indent = vec![];
-    fmt_rec(&processed_logs, &mut indent, None, None, &mut writer).expect("Write failed");
+    fmt_rec(
+        processed_logs,
+        &mut indent,
+        None,
+        None,
+        config,
+        color,
+        &mut writer,
+    )
+    .expect("Write failed");
     writer
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ColorMode, PrettyConfig};
+    #[cfg(feature = "kanidm-tags")]
+    use super::{LogFmt, TimestampFmt, TreeGlyphs};
+    #[cfg(feature = "kanidm-tags")]
+    use crate::kanidm::KanidmEventTag;
+    #[cfg(feature = "kanidm-tags")]
+    use crate::subscriber::{TreeLayer, TreeProcessor};
+    #[cfg(feature = "kanidm-tags")]
+    use std::time::Duration;
+    #[cfg(feature = "kanidm-tags")]
+    use tracing::{info, trace_span, warn};
+
+    #[test]
+    fn color_mode() {
+        assert!(PrettyConfig::new()
+            .with_color(ColorMode::Always)
+            .color(false));
+        assert!(!PrettyConfig::new().with_color(ColorMode::Never).color(true));
+    }
+
+    #[cfg(feature = "kanidm-tags")]
+    #[test]
+    fn pretty_config() {
+        let log = || {
+            let uuid = "5b6e5ad2-8c42-4b3c-9d4e-7f1a2b3c4d5e";
+            trace_span!("root", %uuid).in_scope(|| {
+                trace_span!("child").in_scope(|| admin_info!(user = "sara", "Nested"));
+                info!("Last");
+            });
+        };
+
+        let config = PrettyConfig::new()
+            .with_short_uuid(true)
+            .with_timestamp(false)
+            .with_level(false)
+            .with_emoji(false)
+            .with_values(false)
+            .with_glyphs(TreeGlyphs::ascii());
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let subscriber = TreeLayer::pretty(log_tx.clone())
+            .with_pretty_config(config)
+            .build();
+        tracing::subscriber::with_default(subscriber, log);
+        let subscriber = TreeLayer::pretty(log_tx)
+            .with_pretty_config(
+                PrettyConfig::new()
+                    .with_uuid(false)
+                    .with_tree(false)
+                    .with_tag(false)
+                    .with_timestamp_fmt(TimestampFmt::ElapsedMs),
+            )
+            .build();
+        tracing::subscriber::with_default(subscriber, log);
+
+        let ascii = String::from_utf8(log_rx.recv().unwrap().format(LogFmt::Pretty)).unwrap();
+        let ascii = ascii.lines().collect::<Vec<_>>();
+        assert!(ascii[0].starts_with("5b6e5ad2 root [ "));
+        assert!(ascii[1].starts_with("5b6e5ad2 |- child [ "));
+        assert_eq!(ascii[2], "5b6e5ad2 |  `- [admin.info]: Nested");
+        assert_eq!(ascii[3], "5b6e5ad2 `- [info]: Last");
+
+        let flat = String::from_utf8(log_rx.recv().unwrap().format(LogFmt::Pretty)).unwrap();
+        let flat = flat.lines().collect::<Vec<_>>();
+        assert!(flat[0].starts_with("     0.000 TRACE    root [ "));
+        assert!(flat[2].ends_with(" INFO     💬 Nested | user: \"sara\""));
+    }
+
+    #[cfg(feature = "kanidm-tags")]
+    #[test]
+    fn pretty_ascii() {
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let subscriber = TreeLayer::pretty(log_tx)
+            .with_pretty_config(
+                PrettyConfig::new()
+                    .with_ascii(true)
+                    .with_timestamp_fmt(TimestampFmt::SinceRoot),
+            )
+            .build();
+        tracing::subscriber::with_default(subscriber, || {
+            trace_span!("ascii").in_scope(|| {
+                trace_span!("nested").in_scope(|| security_critical!("Tagged"));
+                warn!("Untagged");
+            });
+        });
+
+        let pretty = String::from_utf8(log_rx.recv().unwrap().format(LogFmt::Pretty)).unwrap();
+        assert!(pretty.is_ascii());
+
+        let lines = pretty.lines().collect::<Vec<_>>();
+        assert!(lines[1].contains(" |- nested [ "));
+        assert!(lines[2].ends_with(" |  `- ERR [security.critical]: Tagged"));
+        assert!(lines[3].ends_with(" `- WRN [warn]: Untagged"));
+    }
+
+    #[cfg(feature = "kanidm-tags")]
+    #[test]
+    fn pretty_color() {
+        let path = std::env::temp_dir().join(format!("pretty-color-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let subscriber = TreeLayer::pretty(log_tx)
+            .with_pretty_config(
+                PrettyConfig::new()
+                    .with_color(ColorMode::Always)
+                    .with_slow_span(Duration::from_millis(5)),
+            )
+            .build();
+        tracing::subscriber::with_default(subscriber, || {
+            trace_span!("colored", output = path).in_scope(|| {
+                trace_span!("slow").in_scope(|| std::thread::sleep(Duration::from_millis(10)));
+                security_critical!("Tinted");
+                warn!("Untagged");
+            });
+        });
+        log_rx.recv().unwrap().process().unwrap();
+
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines = written.lines().collect::<Vec<_>>();
+        assert!(lines[0].contains(" \x1b[35mTRACE   \x1b[0m \x1b[1mcolored\x1b[0m [ \x1b[31m"));
+        assert!(lines[1].contains("\x1b[1mslow\x1b[0m [ \x1b[31m"));
+        assert!(lines[2].contains(" \x1b[31mERROR   \x1b[0m "));
+        assert!(lines[2].contains("[\x1b[31msecurity.critical\x1b[0m]: Tinted"));
+        assert!(lines[3].contains(" \x1b[33mWARN    \x1b[0m "));
+        assert!(lines[3].ends_with("[warn]: Untagged"));
+    }
+}
@@ -1,179 +1,36 @@
 use crate::subscriber::EventTagSet;
-use std::convert::TryFrom;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, EventTagSet)]
+#[tag(path = crate::kanidm::KanidmEventTag, namespace = "kanidm")]
 pub enum KanidmEventTag {
+    #[tag(pretty = "admin.error", emoji = "🚨", level = ERROR, category = Admin)]
     AdminError,
+    #[tag(pretty = "admin.warn", emoji = "🚧", level = WARN, category = Admin)]
     AdminWarn,
+    #[tag(pretty = "admin.info", emoji = "💬", level = INFO, category = Admin)]
     AdminInfo,
+    #[tag(pretty = "request.error", emoji = "🚨", level = ERROR, category = Request)]
     RequestError,
+    #[tag(pretty = "request.warn", emoji = "🚧", level = WARN, category = Request)]
     RequestWarn,
+    #[tag(pretty = "request.info", emoji = "💬", level = INFO, category = Request)]
     RequestInfo,
+    #[tag(pretty = "request.trace", emoji = "📍", level = TRACE, category = Request)]
     RequestTrace,
+    #[tag(pretty = "security.critical", emoji = "🔐", level = ERROR, category = Security, severity = Critical)]
     SecurityCritical,
+    #[tag(pretty = "security.info", emoji = "💬", level = INFO, category = Security, severity = Notice)]
     SecurityInfo,
+    #[tag(pretty = "security.access", emoji = "🔓", level = INFO, category = Security, severity = Notice)]
     SecurityAccess,
+    #[tag(pretty = "filter.error", emoji = "🚨", level = ERROR, category = Filter)]
     FilterError,
+    #[tag(pretty = "filter.warn", emoji = "🚧", level = WARN, category = Filter)]
     FilterWarn,
+    #[tag(pretty = "filter.info", emoji = "💬", level = INFO, category = Filter)]
     FilterInfo,
+    #[tag(pretty = "filter.trace", emoji = "📍", level = TRACE, category = Filter)]
     FilterTrace,
+    #[tag(pretty = "perf.trace", emoji = "📍", level = TRACE, category = Perf)]
     PerfTrace,
 }
-
-impl EventTagSet for KanidmEventTag {
-    fn pretty(self) -> &'static str {
-        match self {
-            KanidmEventTag::AdminError => "admin.error",
-            KanidmEventTag::AdminWarn => "admin.warn",
-            KanidmEventTag::AdminInfo => "admin.info",
-            KanidmEventTag::RequestError => "request.error",
-            KanidmEventTag::RequestWarn => "request.warn",
-            KanidmEventTag::RequestInfo => "request.info",
-            KanidmEventTag::RequestTrace => "request.trace",
-            KanidmEventTag::SecurityCritical => "security.critical",
-            KanidmEventTag::SecurityInfo => "security.info",
-            KanidmEventTag::SecurityAccess => "security.access",
-            KanidmEventTag::FilterError => "filter.error",
-            KanidmEventTag::FilterWarn => "filter.warn",
-            KanidmEventTag::FilterInfo => "filter.info",
-            KanidmEventTag::FilterTrace => "filter.trace",
-            KanidmEventTag::PerfTrace => "perf.trace",
-        }
-    }
-
-    fn emoji(self) -> &'static str {
-        use KanidmEventTag::*;
-        match self {
-            AdminError | RequestError | FilterError => "🚨",
-            AdminWarn | RequestWarn | FilterWarn => "🚧",
-            AdminInfo | RequestInfo | SecurityInfo | FilterInfo => "💬",
-            RequestTrace | FilterTrace | PerfTrace => "📍",
-            SecurityCritical => "🔐",
-            SecurityAccess => "🔓",
-        }
-    }
-}
-
-impl From<KanidmEventTag> for u64 {
-    fn from(tag: KanidmEventTag) -> Self {
-        use KanidmEventTag::*;
-        match tag {
-            AdminError => 0,
-            AdminWarn => 1,
-            AdminInfo => 2,
-            RequestError => 3,
-            RequestWarn => 4,
-            RequestInfo => 5,
-            RequestTrace => 6,
-            SecurityCritical => 7,
-            SecurityInfo => 8,
-            SecurityAccess => 9,
-            FilterError => 10,
-            FilterWarn => 11,
-            FilterInfo => 12,
-            FilterTrace => 13,
-            PerfTrace => 14,
-        }
-    }
-}
-
-impl TryFrom<u64> for KanidmEventTag {
-    type Error = ();
-
-    fn try_from(value: u64) -> Result<Self, Self::Error> {
-        use KanidmEventTag::*;
-        match value {
-            0 => Ok(AdminError),
-            1 => Ok(AdminWarn),
-            2 => Ok(AdminInfo),
-            3 => Ok(RequestError),
-            4 => Ok(RequestWarn),
-            5 => Ok(RequestInfo),
-            6 => Ok(RequestTrace),
-            7 => Ok(SecurityCritical),
-            8 => Ok(SecurityInfo),
-            9 => Ok(SecurityAccess),
-            10 => Ok(FilterError),
-            11 => Ok(FilterWarn),
-            12 => Ok(FilterInfo),
-            13 => Ok(FilterTrace),
-            14 => Ok(PerfTrace),
-            _ => Err(()),
-        }
-    }
-}
-
-#[macro_export]
-macro_rules! admin_error {
-    ($($arg:tt)*) => { tagged_event!(ERROR, crate::kanidm::KanidmEventTag::AdminError, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! admin_warn {
-    ($($arg:tt)*) => { tagged_event!(WARN, crate::kanidm::KanidmEventTag::AdminWarn, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! admin_info {
-    ($($arg:tt)*) => { tagged_event!(INFO, crate::kanidm::KanidmEventTag::AdminInfo, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! request_error {
-    ($($arg:tt)*) => { tagged_event!(ERROR, crate::kanidm::KanidmEventTag::RequestError, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! request_warn {
-    ($($arg:tt)*) => { tagged_event!(WARN, crate::kanidm::KanidmEventTag::RequestWarn, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! request_info {
-    ($($arg:tt)*) => { tagged_event!(INFO, crate::kanidm::KanidmEventTag::RequestInfo, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! request_trace {
-    ($($arg:tt)*) => { tagged_event!(TRACE, crate::kanidm::KanidmEventTag::RequestTrace, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! security_critical {
-    ($($arg:tt)*) => { tagged_event!(INFO, crate::kanidm::KanidmEventTag::SecurityCritical, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! security_info {
-    ($($arg:tt)*) => { tagged_event!(INFO, crate::kanidm::KanidmEventTag::SecurityInfo, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! security_access {
-    ($($arg:tt)*) => { tagged_event!(INFO, crate::kanidm::KanidmEventTag::SecurityAccess, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! filter_error {
-    ($($arg:tt)*) => { tagged_event!(ERROR, crate::kanidm::KanidmEventTag::FilterError, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! filter_warn {
-    ($($arg:tt)*) => { tagged_event!(WARN, crate::kanidm::KanidmEventTag::FilterWarn, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! filter_info {
-    ($($arg:tt)*) => { tagged_event!(INFO, crate::kanidm::KanidmEventTag::FilterInfo, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! filter_trace {
-    ($($arg:tt)*) => { tagged_event!(TRACE, crate::kanidm::KanidmEventTag::FilterTrace, $($arg)*) }
-}
-
-#[macro_export]
-macro_rules! perf_trace {
-    ($($arg:tt)*) => { tagged_event!(TRACE, crate::kanidm::KanidmEventTag::PerfTrace, $($arg)*) }
-}
@@ -1,26 +1,64 @@
-pub mod formatter;
-pub mod subscriber;
-mod timings;
+// Lets `#[derive(EventTagSet)]` expand to `::tracing_tests` paths here too.
+extern crate self as tracing_tests;
 
+// Used by the exported macros and `#[derive(EventTagSet)]`, so crates
+// using them don't need their own dependency on tracing.
+#[doc(hidden)]
+pub mod __private {
+    pub use tracing;
+}
+
+#[cfg(feature = "kanidm-tags")]
 #[macro_use]
 pub mod kanidm;
 
 #[macro_use]
 pub mod macros;
 
-mod middleware;
+pub mod alarm;
+pub mod formatter;
+#[cfg(feature = "log-bridge")]
+pub mod log_bridge;
+#[cfg(feature = "json")]
+pub mod network;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod subscriber;
+pub mod syslog;
+mod timings;
+
+#[cfg(any(
+    feature = "tide-middleware",
+    feature = "tower-middleware",
+    feature = "actix-middleware"
+))]
+pub mod middleware;
 
-#[cfg(test)]
+// Tests for the subscriber itself, and the helpers the other modules'
+// tests share. Most of them log with the kanidm tags.
+#[cfg(all(test, feature = "kanidm-tags"))]
 mod tests {
     use crate::kanidm::KanidmEventTag;
-    use crate::middleware::TreeMiddleware;
+    use crate::subscriber::{CompositeTag, EventTagSet, TagCategory};
+    #[cfg(any(feature = "json", feature = "tokio-worker"))]
     use crate::subscriber::{TreeProcessor, TreeSubscriber};
-    use tokio;
-    use tokio::sync::mpsc::unbounded_channel as unbounded;
-    use tokio::time::{sleep, Duration};
-    use tracing::{self, debug, info, instrument, trace, trace_span};
-    use uuid::Uuid;
+    use crate::syslog::Severity;
+    #[cfg(feature = "json")]
+    use crate::{
+        formatter::{LogFmt, PrettyConfig},
+        subscriber::{Diagnostic, TreeLayer},
+    };
+    #[cfg(feature = "tokio-worker")]
+    use tokio::{
+        sync::mpsc::unbounded_channel as unbounded,
+        time::{sleep, Duration},
+    };
+    #[cfg(feature = "tokio-worker")]
+    use tracing::{debug, instrument, trace};
+    #[cfg(any(feature = "json", feature = "tokio-worker"))]
+    use tracing::{info, trace_span};
 
+    #[cfg(feature = "tokio-worker")]
     #[tokio::test]
     async fn async_tests() {
         let (log_tx, mut log_rx) = unbounded::<TreeProcessor<KanidmEventTag>>();
@@ -29,7 +67,7 @@ mod tests {
         let guard = tracing::subscriber::set_default(subscriber);
 
         #[instrument]
-        async fn first(uuid: Uuid) {
+        async fn first(uuid: &str) {
             filter_error!("First event");
             sleep(Duration::from_millis(500)).await;
             admin_error!("Third event");
@@ -43,7 +81,7 @@ mod tests {
             filter_error!("Fourth event");
         }
 
-        let uuid = Uuid::new_v4();
+        let uuid = "5b6e5ad2-8c42-4b3c-9d4e-7f1a2b3c4d5e";
 
         info!("Going to use this UUID: {}", uuid);
 
@@ -60,6 +98,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "tokio-worker")]
     #[tokio::test]
     async fn deep_spans() {
         let (log_tx, mut log_rx) = unbounded::<TreeProcessor<KanidmEventTag>>();
@@ -67,7 +106,9 @@ mod tests {
         let subscriber = TreeSubscriber::pretty(log_tx);
         let guard = tracing::subscriber::set_default(subscriber);
 
-        trace_span!("try_from_entry_ro", output = "test-out/deep_spans_test.log").in_scope(|| {
+        let path = std::env::temp_dir().join(format!("deep-spans-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        trace_span!("try_from_entry_ro", output = path).in_scope(|| {
             trace_span!("server::internal_search").in_scope(|| {
                 filter_info!("Some filter info...");
                 trace_span!("server::search").in_scope(|| {
@@ -103,27 +144,308 @@ mod tests {
         while let Some(processor) = log_rx.recv().await {
             processor.process().expect("Write failed");
         }
+        std::fs::remove_file(path).unwrap();
 
         println!("done");
     }
 
-    #[tokio::test]
-    async fn middleware_test() {
-        let (log_tx, mut log_rx) = unbounded::<TreeProcessor<KanidmEventTag>>();
+    #[cfg(feature = "json")]
+    #[test]
+    fn std_worker() {
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
 
-        let subscriber = TreeSubscriber::pretty(log_tx);
-        tracing::subscriber::set_global_default(subscriber).unwrap();
+        let worker = std::thread::spawn(move || log_rx.into_iter().map(json_lines).collect());
+
+        let subscriber = TreeSubscriber::json(log_tx);
+        tracing::subscriber::with_default(subscriber, || {
+            trace_span!("std_worker").in_scope(|| {
+                request_info!("Sent to a std thread");
+            });
+        });
+
+        let trees: Vec<Vec<serde_json::Value>> = worker.join().unwrap();
+        assert_eq!(trees.len(), 1);
+
+        let root = &trees[0][0];
+        assert_eq!(root["message"], "std_worker");
+
+        #[cfg(feature = "uuid")]
+        {
+            let uuid = uuid::Uuid::parse_str(root["uuid"].as_str().unwrap()).unwrap();
+            assert_eq!(uuid.get_version_num(), 4);
+        }
+        assert_eq!(trees[0][1]["uuid"], root["uuid"]);
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, EventTagSet)]
+    enum TestTag {
+        #[tag(pretty = "test.info", emoji = "💬", level = INFO)]
+        TestInfo,
+        #[tag(pretty = "test.alert", emoji = "🔔", level = WARN, macro_name = test_alert)]
+        TestWarn,
+        #[tag(pretty = "test.http", emoji = "🌐", level = ERROR)]
+        HTTPError,
+    }
+
+    // Like `TestTag`, without a namespace
+    #[derive(Debug, Clone, Copy, PartialEq, EventTagSet)]
+    enum OtherTestTag {
+        #[tag(pretty = "other.info", emoji = "💬", level = INFO)]
+        OtherTestInfo,
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn derived_tag_set() {
+        use std::convert::TryFrom;
+
+        assert_eq!(u64::from(TestTag::TestWarn), 1);
+        assert_eq!(TestTag::try_from(0), Ok(TestTag::TestInfo));
+        assert_eq!(TestTag::try_from(3), Err(()));
+        assert_eq!(TestTag::TestWarn.pretty(), "test.alert");
+
+        let lines = json_tree::<TestTag>(|| {
+            test_info!("Derived info");
+            test_alert!("Derived warning");
+            // Runs of capitals are kept together
+            http_error!("Derived error");
+        });
+
+        assert_eq!(lines[1]["tag"], "test.info");
+        assert_eq!(lines[1]["level"], "INFO");
+        assert_eq!(lines[2]["tag"], "test.alert");
+        assert_eq!(lines[2]["level"], "WARN");
+        assert_eq!(lines[3]["tag"], "test.http");
+    }
+
+    #[test]
+    fn tag_metadata() {
+        let tag = KanidmEventTag::SecurityCritical;
+        assert_eq!(tag.level(), tracing::Level::ERROR);
+        assert_eq!(tag.category(), TagCategory::Security);
+        assert_eq!(tag.severity(), Severity::Critical);
+
+        // Derived defaults
+        assert_eq!(TestTag::TestInfo.category(), TagCategory::Other);
+        assert_eq!(TestTag::TestWarn.severity(), Severity::Warning);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn exported_macros() {
+        let lines = json_tree::<KanidmEventTag>(|| {
+            crate::tagged_event!(WARN, KanidmEventTag::AdminInfo, "By path");
+        });
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(lines[1]["tag"], "admin.info");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn unknown_event_tag() {
+        let lines = json_tree::<TestTag>(|| {
+            filter_info!("From another tag set");
+        });
+        assert_eq!(lines[1]["tag"], serde_json::Value::Null);
+        assert_eq!(
+            lines[1]["values"]["event_tag"],
+            KanidmEventTag::FilterInfo.event_tag().to_string()
+        );
+        assert_eq!(lines[1]["message"], "From another tag set");
+
+        // Strict mode also reports it
+        let (log_tx, _log_rx) = std::sync::mpsc::channel::<TreeProcessor<TestTag>>();
+        let (diag_tx, diag_rx) = std::sync::mpsc::channel();
+        let subscriber = TreeLayer::json(log_tx).with_diagnostics(diag_tx).build();
+        tracing::subscriber::with_default(subscriber, || {
+            filter_info!("From another tag set");
+        });
+
+        match diag_rx.try_recv().unwrap() {
+            Diagnostic::UnknownEventTag {
+                tag,
+                tag_set,
+                message,
+            } => {
+                assert_eq!(tag, KanidmEventTag::FilterInfo.event_tag());
+                assert!(tag_set.ends_with("TestTag"));
+                assert_eq!(message, "From another tag set");
+            }
+            other => panic!("Expected an unknown event tag, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn composite_tags() {
+        type Tags = CompositeTag<KanidmEventTag, TestTag>;
+
+        // Both sets number their tags from 0
+        assert_eq!(u64::from(KanidmEventTag::AdminError), 0);
+        assert_eq!(u64::from(TestTag::TestInfo), 0);
+        assert_ne!(
+            KanidmEventTag::AdminError.event_tag(),
+            TestTag::TestInfo.event_tag()
+        );
+
+        let lines = json_tree::<Tags>(|| {
+            admin_error!("From kanidm");
+            test_info!("From the tests");
+        });
+        assert_eq!(lines[1]["tag"], "admin.error");
+        assert_eq!(lines[2]["tag"], "test.info");
+        assert_eq!(lines[2]["values"], serde_json::json!({}));
+
+        let tag = Tags::from_event_tag(TestTag::TestWarn.event_tag());
+        assert!(matches!(tag, Some(CompositeTag::Second(TestTag::TestWarn))));
+        assert_eq!(tag.unwrap().level(), tracing::Level::WARN);
+    }
+
+    #[test]
+    fn derived_namespaces() {
+        type Tags = CompositeTag<TestTag, OtherTestTag>;
+
+        // Without a `namespace`, each set is namespaced by its enum name
+        assert_eq!(TestTag::NAMESPACE, "TestTag");
+        assert_ne!(
+            TestTag::TestInfo.event_tag(),
+            OtherTestTag::OtherTestInfo.event_tag()
+        );
+        assert!(matches!(
+            Tags::from_event_tag(OtherTestTag::OtherTestInfo.event_tag()),
+            Some(CompositeTag::Second(OtherTestTag::OtherTestInfo))
+        ));
+        assert!(matches!(
+            Tags::from_event_tag(TestTag::TestInfo.event_tag()),
+            Some(CompositeTag::First(TestTag::TestInfo))
+        ));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn error_chains() {
+        #[derive(Debug)]
+        struct ConfigError(std::num::ParseIntError);
 
-        tokio::spawn(async move {
-            while let Some(processor) = log_rx.recv().await {
-                processor.process().expect("Write failed");
+        impl std::fmt::Display for ConfigError {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("Invalid config")
             }
+        }
+
+        impl std::error::Error for ConfigError {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let error = ConfigError("port".parse::<u16>().unwrap_err());
+
+        let log = || {
+            tracing::error!(error = &error as &dyn std::error::Error, "Startup failed");
+        };
+
+        let lines = json_tree::<KanidmEventTag>(log);
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        tracing::subscriber::with_default(TreeSubscriber::pretty(log_tx), || {
+            trace_span!("error_chains").in_scope(log)
         });
 
-        let mut app = tide::new();
-        app.with(TreeMiddleware::new("test_out/middleware_test.log"));
-        app.at("/").get(|_| async { Ok("Hello, world!") });
-        app.at("/sara").get(|_| async { Ok("omg it's sara!") });
-        app.listen("127.0.0.1:8080").await.unwrap();
+        let pretty = String::from_utf8(log_rx.recv().unwrap().format(LogFmt::Pretty)).unwrap();
+
+        assert_eq!(lines[1]["values"]["error"], "Invalid config");
+        assert_eq!(
+            lines[1]["errors"]["error"],
+            serde_json::json!(["Invalid config", "invalid digit found in string"])
+        );
+
+        let pretty = pretty.lines().collect::<Vec<_>>();
+        assert_eq!(pretty.len(), 3);
+        assert!(pretty[1].ends_with("[error]: Startup failed | error: Invalid config"));
+        assert!(pretty[2].ends_with("      error caused by: invalid digit found in string"));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn event_callsites() {
+        let log = || info!("Here");
+        let line = line!() - 1;
+        let tree = || trace_span!("event_callsites").in_scope(log);
+
+        let lines = json_tree::<KanidmEventTag>(log);
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        tracing::subscriber::with_default(TreeSubscriber::pretty(log_tx.clone()), tree);
+        let subscriber = TreeLayer::pretty(log_tx)
+            .with_pretty_config(PrettyConfig::new().with_callsite(true))
+            .build();
+        tracing::subscriber::with_default(subscriber, tree);
+
+        assert_eq!(lines[1]["target"], module_path!());
+        assert_eq!(lines[1]["module_path"], module_path!());
+        assert_eq!(lines[1]["file"], file!());
+        assert_eq!(lines[1]["line"], line);
+
+        let plain = String::from_utf8(log_rx.recv().unwrap().format(LogFmt::Pretty)).unwrap();
+        assert!(plain.lines().nth(1).unwrap().ends_with("[info]: Here"));
+
+        let with_callsite =
+            String::from_utf8(log_rx.recv().unwrap().format(LogFmt::Pretty)).unwrap();
+        let suffix = format!("[info]: Here @ {}:{}", file!(), line);
+        assert!(with_callsite.lines().nth(1).unwrap().ends_with(&suffix));
+    }
+
+    #[cfg(all(feature = "tokio-worker", feature = "json"))]
+    #[tokio::test]
+    async fn thread_info() {
+        use tracing::Instrument;
+
+        let (log_tx, mut log_rx) = unbounded::<TreeProcessor<KanidmEventTag>>();
+        let subscriber = TreeLayer::json(log_tx).with_threads().build();
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let span = trace_span!("thread_info");
+        async { info!("In the test") }
+            .instrument(span.clone())
+            .await;
+        // The current thread runtime polls it on this thread too
+        tokio::spawn(async { info!("In a task") }.instrument(span))
+            .await
+            .unwrap();
+
+        drop(guard);
+
+        let lines = json_lines(log_rx.recv().await.unwrap());
+        let thread = std::thread::current();
+        assert_eq!(lines[0]["threads"].as_array().unwrap().len(), 2);
+        assert_eq!(lines[1]["thread"]["name"], thread.name().unwrap());
+        assert_eq!(lines[1]["thread"]["task"], serde_json::Value::Null);
+        assert_eq!(lines[2]["thread"]["id"], lines[1]["thread"]["id"]);
+        assert!(lines[2]["thread"]["task"].is_string());
+    }
+
+    // The lines of a tree formatted as JSON.
+    #[cfg(feature = "json")]
+    pub(crate) fn json_lines<E: EventTagSet>(
+        processor: TreeProcessor<E>,
+    ) -> Vec<serde_json::Value> {
+        processor
+            .format(LogFmt::Json)
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    // Logs in a root span named `json_tree` under `TreeSubscriber::json`,
+    // and returns the lines of that tree.
+    #[cfg(feature = "json")]
+    pub(crate) fn json_tree<E: EventTagSet>(log: impl FnOnce()) -> Vec<serde_json::Value> {
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<E>>();
+        tracing::subscriber::with_default(TreeSubscriber::json(log_tx), || {
+            trace_span!("json_tree").in_scope(log)
+        });
+        json_lines(log_rx.recv().unwrap())
     }
 }
@@ -0,0 +1,138 @@
+use tracing::Event;
+use tracing_log::log::SetLoggerError;
+use tracing_log::{LogTracer, NormalizeEvent};
+
+use crate::subscriber::{EventTagSet, TreeEvent};
+
+// Routes records from the `log` crate into the current span's tree, as
+// events with the record's target, module path, file and line. Records from
+// outside any span are handled like untagged events.
+
+// Installs the bridge as the global `log` logger, which can only be done
+// once per process.
+pub fn init() -> Result<(), SetLoggerError> {
+    LogTracer::init()
+}
+
+// Tags for `log` records, which can't carry one themselves. See
+// `TreeLayer::with_log_tags`.
+#[derive(Clone, Debug)]
+pub struct LogTags<E> {
+    targets: Vec<(String, E)>,
+    default: Option<E>,
+}
+
+impl<E: EventTagSet> LogTags<E> {
+    pub fn new() -> Self {
+        LogTags {
+            targets: vec![],
+            default: None,
+        }
+    }
+
+    // Records whose target is `target` or one of its submodules. The
+    // longest matching target wins.
+    pub fn with_target(mut self, target: impl Into<String>, tag: E) -> Self {
+        self.targets.push((target.into(), tag));
+        self
+    }
+
+    // Records matching no target, which are otherwise untagged.
+    pub fn with_default(mut self, tag: E) -> Self {
+        self.default = Some(tag);
+        self
+    }
+
+    fn tag(&self, target: &str) -> Option<E> {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, tag)| *tag)
+            .or(self.default)
+    }
+}
+
+impl<E: EventTagSet> Default for LogTags<E> {
+    fn default() -> Self {
+        LogTags::new()
+    }
+}
+
+// Replaces the `log.*` fields `LogTracer` adds with the record's own
+// callsite, which would otherwise be `LogTracer`'s, and tags the event if
+// it isn't already.
+pub(crate) fn bridge<E: EventTagSet>(
+    mut tree_event: TreeEvent<E>,
+    event: &Event,
+    tags: Option<&LogTags<E>>,
+) -> TreeEvent<E> {
+    let metadata = match event.normalized_metadata() {
+        Some(metadata) => metadata,
+        None => return tree_event,
+    };
+
+    tree_event
+        .values
+        .retain(|(name, _)| !name.starts_with("log."));
+    tree_event.target = metadata.target().to_string();
+    tree_event.module_path = metadata.module_path().map(str::to_string);
+    tree_event.file = metadata.file().map(str::to_string);
+    tree_event.line = metadata.line();
+
+    if tree_event.tag.is_none() {
+        tree_event.tag = tags.and_then(|tags| tags.tag(metadata.target()));
+    }
+
+    tree_event
+}
+
+#[cfg(all(test, feature = "kanidm-tags", feature = "json"))]
+mod tests {
+    use super::LogTags;
+    use crate::kanidm::KanidmEventTag;
+    use crate::subscriber::{TreeLayer, TreeProcessor};
+    use crate::tests::json_lines;
+    use tracing::trace_span;
+    use tracing_log::{log, LogTracer};
+
+    #[test]
+    fn log_bridge() {
+        // What `log_bridge::init` does, without tide's request logs ending
+        // up in the middleware tests as trees of their own
+        let _ = LogTracer::builder().ignore_crate("tide").init();
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let subscriber = TreeLayer::json(log_tx)
+            .with_log_tags(
+                LogTags::new()
+                    .with_target("hyper", KanidmEventTag::RequestInfo)
+                    .with_target("hyper::proto", KanidmEventTag::RequestTrace)
+                    .with_default(KanidmEventTag::AdminInfo),
+            )
+            .build();
+        tracing::subscriber::with_default(subscriber, || {
+            trace_span!("log_bridge").in_scope(|| {
+                log::info!(target: "hyper::client::pool", "Reusing connection");
+                log::warn!(target: "hyper::proto::h1", "Parse error");
+                log::info!(target: "hyperion", "Not hyper");
+            });
+        });
+
+        let lines = json_lines(log_rx.recv().unwrap());
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1]["message"], "Reusing connection");
+        assert_eq!(lines[1]["tag"], "request.info");
+        assert_eq!(lines[1]["target"], "hyper::client::pool");
+        assert_eq!(lines[1]["module_path"], module_path!());
+        assert_eq!(lines[1]["file"], file!());
+        assert_eq!(lines[1]["values"], serde_json::json!({}));
+        assert_eq!(lines[2]["level"], "WARN");
+        assert_eq!(lines[2]["tag"], "request.trace");
+        assert_eq!(lines[3]["tag"], "admin.info");
+    }
+}
@@ -1,18 +1,16 @@
 #[macro_export]
 macro_rules! alarm {
-    ($($arg:tt)*) => {{
-        use tracing;
-        tracing::error!(alarm = true, $($arg)*);
-    }};
+    ($($arg:tt)*) => {
+        $crate::__private::tracing::error!(alarm = true, $($arg)*)
+    };
 }
 
-#[allow(unused_macros)]
+// Logs an event tagged with any `EventTagSet`, e.g.
+// `tagged_event!(WARN, MyTag::Quota, user = %name, "Over quota")`.
+#[macro_export]
 macro_rules! tagged_event {
     ($level:ident, $logtag:path, $($arg:tt)*) => {{
-        use tracing;
-        fn assert_eventtagset<T: crate::subscriber::EventTagSet>(_x: &T) {}
-        assert_eventtagset(&$logtag);
-        let event_tag: u64 = $logtag.into();
-        tracing::event!(tracing::Level::$level, event_tag, $($arg)*)
+        let event_tag: u64 = $crate::subscriber::EventTagSet::event_tag($logtag);
+        $crate::__private::tracing::event!($crate::__private::tracing::Level::$level, event_tag, $($arg)*)
     }}
 }
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use tracing::{self, trace_span, Instrument};
+
+use super::{log_response, RequestId, TraceContext, TRACEPARENT, TRACESTATE};
+
+// actix-web equivalent of `TreeMiddleware`, used with `App::wrap`.
+
+#[derive(Clone, Copy, Debug)]
+pub struct ActixTreeMiddleware {
+    output: &'static str,
+}
+
+pub struct ActixTreeService<S> {
+    service: S,
+    output: &'static str,
+}
+
+impl ActixTreeMiddleware {
+    pub fn new(output: &'static str) -> Self {
+        ActixTreeMiddleware { output }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ActixTreeMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ActixTreeService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ActixTreeService {
+            service,
+            output: self.output,
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for ActixTreeService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // Already logging into a tree further up the stack
+        if req.extensions().get::<RequestId>().is_some() {
+            return Box::pin(self.service.call(req));
+        }
+
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+
+        let trace = TraceContext::new(header(TRACEPARENT), header(TRACESTATE), None);
+        let uuid = trace.uuid;
+        req.extensions_mut().insert(RequestId(uuid));
+
+        let span = trace_span!("actix-request", %uuid, output = self.output);
+
+        let response = span.in_scope(|| {
+            request_info!(
+                http.method = req.method().as_str(),
+                path = req.path(),
+                "Request received"
+            );
+            self.service.call(req)
+        });
+
+        Box::pin(
+            async move {
+                match response.await {
+                    Ok(mut response) => {
+                        let status = response.status();
+                        let error = response
+                            .response()
+                            .error()
+                            .map(|error| (error as &dyn fmt::Display, "actix_web::Error"));
+                        log_response(
+                            status.as_u16(),
+                            status.canonical_reason().unwrap_or("?"),
+                            error,
+                        );
+
+                        let headers = response.headers_mut();
+                        if let Ok(traceparent) = HeaderValue::from_str(&trace.traceparent()) {
+                            headers.insert(HeaderName::from_static(TRACEPARENT), traceparent);
+                        }
+                        if let Some(tracestate) = trace
+                            .tracestate
+                            .and_then(|tracestate| HeaderValue::from_str(&tracestate).ok())
+                        {
+                            headers.insert(HeaderName::from_static(TRACESTATE), tracestate);
+                        }
+
+                        Ok(response)
+                    }
+                    Err(error) => {
+                        request_error!(
+                            message = display(&error),
+                            error_type = "actix_web::Error",
+                            "Service error -> No response sent"
+                        );
+                        Err(error)
+                    }
+                }
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::ActixTreeMiddleware;
+    use crate::kanidm::KanidmEventTag;
+    use crate::subscriber::{TreeProcessor, TreeSubscriber};
+    use crate::tests::json_lines;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn actix_middleware() {
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let guard = tracing::subscriber::set_default(TreeSubscriber::json(log_tx));
+
+        let app = test::init_service(App::new().wrap(ActixTreeMiddleware::new("stdout")).route(
+            "/",
+            web::get().to(|| async {
+                admin_error!("Handler failed");
+                HttpResponse::InternalServerError().finish()
+            }),
+        ))
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+
+        drop(guard);
+
+        assert_eq!(res.status(), 500);
+        assert!(res.headers().contains_key("traceparent"));
+
+        let lines = json_lines(log_rx.recv().unwrap());
+        assert_eq!(lines[0]["message"], "actix-request");
+        assert_eq!(lines[1]["message"], "Request received");
+        assert_eq!(lines[1]["values"]["path"], "\"/\"");
+        assert_eq!(lines[2]["tag"], "admin.error");
+        assert_eq!(lines[2]["spans"], serde_json::json!(["actix-request"]));
+        assert_eq!(lines[3]["tag"], "request.error");
+        assert_eq!(lines[3]["level"], "ERROR");
+    }
+}
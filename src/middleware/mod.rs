@@ -0,0 +1,146 @@
+use std::fmt;
+
+use uuid::Uuid;
+
+#[cfg(feature = "tide-middleware")]
+mod tide;
+#[cfg(feature = "tide-middleware")]
+pub use self::tide::{MatchedRoute, RequestConfig, RequestFields, TreeMiddleware};
+
+#[cfg(feature = "tower-middleware")]
+mod tower;
+#[cfg(feature = "tower-middleware")]
+pub use self::tower::{TowerTreeMiddleware, TowerTreeService};
+
+#[cfg(feature = "actix-middleware")]
+mod actix;
+#[cfg(feature = "actix-middleware")]
+pub use self::actix::{ActixTreeMiddleware, ActixTreeService};
+
+pub(crate) const TRACEPARENT: &str = "traceparent";
+pub(crate) const TRACESTATE: &str = "tracestate";
+
+// Uuid of the tree a request is logged into, available to handlers
+// through `req.ext::<RequestId>()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestId(pub Uuid);
+
+// W3C trace context of a request. The trace id doubles as the root span uuid.
+pub(crate) struct TraceContext {
+    pub uuid: Uuid,
+    pub flags: u8,
+    pub tracestate: Option<String>,
+}
+
+// Classifies a response by its status: server errors are logged as errors,
+// client errors as warnings and everything else as info.
+pub(crate) fn log_response(status: u16, reason: &str, error: Option<(&dyn fmt::Display, &str)>) {
+    if (500..600).contains(&status) {
+        if let Some((error, error_type)) = error {
+            request_error!(
+                message = display(error),
+                error_type,
+                status = format_args!("{} - {}", status, reason),
+                "Internal error -> Response sent"
+            );
+        } else {
+            request_error!(
+                status = format_args!("{} - {}", status, reason),
+                "Internal error -> Response sent"
+            );
+        }
+    } else if (400..500).contains(&status) {
+        if let Some((error, error_type)) = error {
+            request_warn!(
+                message = display(error),
+                error_type,
+                status = format_args!("{} - {}", status, reason),
+                "Client error --> Response sent"
+            );
+        } else {
+            request_warn!(
+                status = format_args!("{} - {}", status, reason),
+                "Client error --> Response sent"
+            );
+        }
+    } else {
+        request_info!(
+            status = format_args!("{} - {}", status, reason),
+            "--> Response sent"
+        );
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl TraceContext {
+    // Falls back to the request id header, then to a fresh uuid.
+    pub fn new(
+        traceparent: Option<&str>,
+        tracestate: Option<&str>,
+        request_id: Option<&str>,
+    ) -> Self {
+        match traceparent.and_then(parse_traceparent) {
+            Some((uuid, flags)) => TraceContext {
+                uuid,
+                flags,
+                tracestate: tracestate.map(str::to_string),
+            },
+            None => TraceContext {
+                uuid: request_id
+                    .and_then(|id| Uuid::parse_str(id.trim()).ok())
+                    .unwrap_or_else(Uuid::new_v4),
+                flags: 0,
+                tracestate: None,
+            },
+        }
+    }
+
+    // The `traceparent` for the response, with this service as the parent.
+    pub fn traceparent(&self) -> String {
+        let parent_id = &Uuid::new_v4().to_simple().to_string()[..16];
+        format!(
+            "00-{}-{}-{:02x}",
+            self.uuid.to_simple(),
+            parent_id,
+            self.flags
+        )
+    }
+}
+
+// `version-traceid-parentid-flags`, see https://www.w3.org/TR/trace-context/
+fn parse_traceparent(traceparent: &str) -> Option<(Uuid, u8)> {
+    fn is_hex(s: &str, len: usize) -> bool {
+        s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    }
+
+    let mut parts = traceparent.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+
+    // Later versions may append fields, but version 00 has exactly four.
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+
+    if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+
+    let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+    let parent_id = u64::from_str_radix(parent_id, 16).ok()?;
+    if trace_id == 0 || parent_id == 0 {
+        return None;
+    }
+
+    Some((
+        Uuid::from_u128(trace_id),
+        u8::from_str_radix(flags, 16).ok()?,
+    ))
+}
@@ -0,0 +1,570 @@
+use std::fmt;
+use std::time::Instant;
+
+use tide::http::{self, headers::USER_AGENT};
+use tide::{self, Middleware, Next, Request};
+use tracing::level_filters::LevelFilter;
+use tracing::{
+    self,
+    field::{display, Empty},
+    trace_span, Instrument, Span,
+};
+
+use super::{log_response, RequestId, TraceContext, TRACEPARENT, TRACESTATE};
+use crate::subscriber::{self, SpanConfig, TreeIo};
+
+// Modeled after:
+// https://docs.rs/tide/0.16.0/src/tide/log/middleware.rs.html#23-96
+
+type ConfigFn = dyn Fn(&http::Request) -> RequestConfig + Send + Sync;
+
+pub struct TreeMiddleware {
+    output: &'static str,
+    config: Option<Box<ConfigFn>>,
+    request_id_header: Option<&'static str>,
+    response_header: Option<&'static str>,
+    fields: RequestFields,
+}
+
+// Where and how much a single request logs, see `TreeMiddleware::with_config`.
+#[derive(Clone, Debug)]
+pub struct RequestConfig {
+    // The middleware's own output when not set
+    output: Option<String>,
+    max_level: LevelFilter,
+    sampled: bool,
+}
+
+// Which request/response details are recorded on the `tide-request` span.
+// The query string is off by default since it may carry tokens. Headers
+// other than User-Agent are never recorded, so credentials in
+// Authorization, Cookie and friends can't end up in the logs.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestFields {
+    remote_addr: bool,
+    user_agent: bool,
+    query: bool,
+    version: bool,
+    request_size: bool,
+    response_size: bool,
+    route: bool,
+    latency: bool,
+}
+
+// Tide doesn't say which route pattern matched, so routes can declare it:
+// `app.at("/users/:id").with(MatchedRoute("/users/:id")).get(...)`
+#[derive(Clone, Copy, Debug)]
+pub struct MatchedRoute(pub &'static str);
+
+impl TreeMiddleware {
+    pub fn new(output: &'static str) -> Self {
+        TreeMiddleware {
+            output,
+            config: None,
+            request_id_header: None,
+            response_header: None,
+            fields: RequestFields::default(),
+        }
+    }
+
+    // Also accept a uuid from this header (e.g. `X-Request-Id`) when there's
+    // no `traceparent`, and echo it back on the response.
+    pub fn with_request_id_header(mut self, header: &'static str) -> Self {
+        self.request_id_header = Some(header);
+        self
+    }
+
+    // Send the root span uuid back in this header, without trusting
+    // incoming ids like `with_request_id_header` does.
+    pub fn with_response_header(mut self, header: &'static str) -> Self {
+        self.response_header = Some(header);
+        self
+    }
+
+    // Decide the output, level and sampling for each request. Requests
+    // whose config doesn't set an output use the one passed to `new`:
+    //
+    // TreeMiddleware::new("stdout").with_config(|req| match req.url().path() {
+    //     "/health" => RequestConfig::discard(),
+    //     path if path.starts_with("/admin/") => RequestConfig::new("audit.log"),
+    //     _ => RequestConfig::default().max_level(LevelFilter::INFO),
+    // })
+    pub fn with_config<F>(mut self, config: F) -> Self
+    where
+        F: Fn(&http::Request) -> RequestConfig + Send + Sync + 'static,
+    {
+        self.config = Some(Box::new(config));
+        self
+    }
+
+    pub fn with_fields(mut self, fields: RequestFields) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    // This method is only called when `instrument`ed.
+    async fn log<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let start = Instant::now();
+        let span = Span::current();
+        let fields = self.fields;
+
+        if let Some(remote_addr) = req.remote().filter(|_| fields.remote_addr) {
+            span.record("http.remote_addr", display(remote_addr));
+        }
+        if let Some(user_agent) = req.header(USER_AGENT).filter(|_| fields.user_agent) {
+            span.record("http.user_agent", display(user_agent.last()));
+        }
+        if let Some(query) = req.url().query().filter(|_| fields.query) {
+            span.record("http.query", display(query));
+        }
+        if let Some(version) = req.version().filter(|_| fields.version) {
+            span.record("http.version", display(version));
+        }
+        if let Some(len) = req.len().filter(|_| fields.request_size) {
+            span.record("http.request_size", len as u64);
+        }
+
+        let path = req.url().path().to_string();
+        let method = req.method();
+
+        let path = path.as_str();
+        let method = method.as_ref();
+
+        request_info!(http.method = method, path, "Request received");
+
+        let response = next.run(req).await;
+        let status = response.status();
+
+        if let Some(MatchedRoute(route)) = response.ext().filter(|_| fields.route) {
+            span.record("http.route", display(route));
+        }
+        if let Some(len) = response.len().filter(|_| fields.response_size) {
+            span.record("http.response_size", len as u64);
+        }
+        if fields.latency {
+            span.record("http.latency_us", start.elapsed().as_micros() as u64);
+        }
+
+        let error = response
+            .error()
+            .map(|error| (error as &dyn fmt::Display, error.type_name().unwrap_or("?")));
+        log_response(status as u16, status.canonical_reason(), error);
+
+        Ok(response)
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TreeMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // Already logging into a tree further up the middleware stack
+        if req.ext::<RequestId>().is_some() {
+            return Ok(next.run(req).await);
+        }
+
+        let header = |name: &str| req.header(name).map(|values| values.last().as_str());
+
+        let trace = TraceContext::new(
+            header(TRACEPARENT),
+            header(TRACESTATE),
+            self.request_id_header.and_then(header),
+        );
+        let uuid = trace.uuid;
+        req.set_ext(RequestId(uuid));
+
+        let config = match self.config {
+            Some(ref config) => config(req.as_ref()),
+            None => RequestConfig::default(),
+        };
+
+        let span = trace_span!(
+            "tide-request",
+            %uuid,
+            http.remote_addr = Empty,
+            http.user_agent = Empty,
+            http.query = Empty,
+            http.version = Empty,
+            http.request_size = Empty,
+            http.route = Empty,
+            http.response_size = Empty,
+            http.latency_us = Empty,
+        );
+        subscriber::configure_span(&span, config.span_config(self.output));
+
+        let mut response = self.log(req, next).instrument(span).await?;
+
+        response.insert_header(TRACEPARENT, trace.traceparent());
+        if let Some(ref tracestate) = trace.tracestate {
+            response.insert_header(TRACESTATE, tracestate.as_str());
+        }
+        for header in self.request_id_header.iter().chain(&self.response_header) {
+            response.insert_header(*header, uuid.to_string());
+        }
+
+        Ok(response)
+    }
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for MatchedRoute {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut response = next.run(req).await;
+        response.insert_ext(*self);
+        Ok(response)
+    }
+}
+
+impl RequestConfig {
+    pub fn new(output: impl Into<String>) -> Self {
+        RequestConfig {
+            output: Some(output.into()),
+            ..RequestConfig::default()
+        }
+    }
+
+    // Log nothing for this request.
+    pub fn discard() -> Self {
+        RequestConfig::default().sampled(false)
+    }
+
+    // Events more verbose than this are left out of the request's tree.
+    pub fn max_level(mut self, max_level: LevelFilter) -> Self {
+        self.max_level = max_level;
+        self
+    }
+
+    // Unsampled requests are dropped without building their tree.
+    pub fn sampled(mut self, sampled: bool) -> Self {
+        self.sampled = sampled;
+        self
+    }
+
+    fn span_config(&self, default_output: &str) -> SpanConfig {
+        if !self.sampled {
+            return SpanConfig {
+                out: None,
+                max_level: LevelFilter::OFF,
+            };
+        }
+
+        let output = self.output.as_deref().unwrap_or(default_output);
+        SpanConfig {
+            out: Some(TreeIo::from_output(output)),
+            max_level: self.max_level,
+        }
+    }
+}
+
+impl Default for RequestConfig {
+    fn default() -> Self {
+        RequestConfig {
+            output: None,
+            max_level: LevelFilter::TRACE,
+            sampled: true,
+        }
+    }
+}
+
+impl Default for RequestFields {
+    fn default() -> Self {
+        RequestFields {
+            remote_addr: true,
+            user_agent: true,
+            query: false,
+            version: true,
+            request_size: true,
+            response_size: true,
+            route: true,
+            latency: true,
+        }
+    }
+}
+
+impl RequestFields {
+    pub fn none() -> Self {
+        RequestFields {
+            remote_addr: false,
+            user_agent: false,
+            query: false,
+            version: false,
+            request_size: false,
+            response_size: false,
+            route: false,
+            latency: false,
+        }
+    }
+
+    pub fn remote_addr(mut self, enabled: bool) -> Self {
+        self.remote_addr = enabled;
+        self
+    }
+
+    pub fn user_agent(mut self, enabled: bool) -> Self {
+        self.user_agent = enabled;
+        self
+    }
+
+    pub fn query(mut self, enabled: bool) -> Self {
+        self.query = enabled;
+        self
+    }
+
+    pub fn version(mut self, enabled: bool) -> Self {
+        self.version = enabled;
+        self
+    }
+
+    pub fn request_size(mut self, enabled: bool) -> Self {
+        self.request_size = enabled;
+        self
+    }
+
+    pub fn response_size(mut self, enabled: bool) -> Self {
+        self.response_size = enabled;
+        self
+    }
+
+    pub fn route(mut self, enabled: bool) -> Self {
+        self.route = enabled;
+        self
+    }
+
+    pub fn latency(mut self, enabled: bool) -> Self {
+        self.latency = enabled;
+        self
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::{MatchedRoute, RequestConfig, RequestFields, TreeMiddleware};
+    use crate::kanidm::KanidmEventTag;
+    use crate::middleware::RequestId;
+    use crate::subscriber::{TreeIo, TreeProcessed, TreeProcessor, TreeSubscriber};
+    use crate::tests::json_lines;
+    use tide::http::{Method, Request, Response, Url};
+    use tracing::debug;
+    use tracing::level_filters::LevelFilter;
+    use uuid::Uuid;
+
+    async fn tide_request_root(
+        app: &tide::Server<()>,
+        req: Request,
+    ) -> (Response, serde_json::Value) {
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+
+        let subscriber = TreeSubscriber::json(log_tx);
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let res: Response = app.respond(req).await.unwrap();
+
+        drop(guard);
+
+        let root = json_lines(log_rx.recv().unwrap()).remove(0);
+        assert_eq!(root["message"], "tide-request");
+
+        (res, root)
+    }
+
+    async fn tide_request_uuid(app: &tide::Server<()>, req: Request) -> (Response, String) {
+        let (res, root) = tide_request_root(app, req).await;
+        (res, root["uuid"].as_str().unwrap().to_string())
+    }
+
+    #[async_std::test]
+    async fn middleware_traceparent() {
+        let mut app = tide::new();
+        app.with(TreeMiddleware::new("stdout").with_request_id_header("X-Request-Id"));
+        app.at("/").get(|_| async { Ok("Hello, world!") });
+
+        let url = Url::parse("http://localhost/").unwrap();
+
+        // The trace id becomes the root span uuid, and is echoed back
+        let mut req = Request::new(Method::Get, url.clone());
+        req.insert_header(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        );
+        req.insert_header("tracestate", "congo=t61rcWkgMzE");
+        let (res, uuid) = tide_request_uuid(&app, req).await;
+        assert_eq!(uuid, "4bf92f35-77b3-4da6-a3ce-929d0e0e4736");
+        let traceparent = res["traceparent"].as_str();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert_ne!(&traceparent[36..52], "00f067aa0ba902b7");
+        assert_eq!(res["tracestate"].as_str(), "congo=t61rcWkgMzE");
+        assert_eq!(res["X-Request-Id"].as_str(), uuid);
+
+        // Falls back to the request id header
+        let mut req = Request::new(Method::Get, url.clone());
+        req.insert_header("X-Request-Id", "a1a2a3a4-b1b2-c1c2-d1d2-d3d4d5d6d7d8");
+        let (res, uuid) = tide_request_uuid(&app, req).await;
+        assert_eq!(uuid, "a1a2a3a4-b1b2-c1c2-d1d2-d3d4d5d6d7d8");
+        assert_eq!(res["X-Request-Id"].as_str(), uuid);
+
+        // Invalid trace context is ignored and a fresh uuid is minted
+        let mut req = Request::new(Method::Get, url);
+        req.insert_header(
+            "traceparent",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        );
+        let (res, uuid) = tide_request_uuid(&app, req).await;
+        assert!(Uuid::parse_str(&uuid).is_ok());
+        assert_ne!(uuid, "00000000-0000-0000-0000-000000000000");
+        assert!(res.header("tracestate").is_none());
+        assert_eq!(res["X-Request-Id"].as_str(), uuid);
+    }
+
+    #[async_std::test]
+    async fn middleware_request_id() {
+        let mut app = tide::new();
+        app.with(TreeMiddleware::new("stdout").with_response_header("X-Request-Id"));
+        // Nested middleware keeps logging into the outer tree
+        app.with(TreeMiddleware::new("stderr"));
+        app.at("/").get(|req: tide::Request<()>| async move {
+            let RequestId(uuid) = *req.ext::<RequestId>().unwrap();
+            Ok(uuid.to_string())
+        });
+
+        let mut req = Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        req.insert_header("X-Request-Id", "a1a2a3a4-b1b2-c1c2-d1d2-d3d4d5d6d7d8");
+        let (mut res, uuid) = tide_request_uuid(&app, req).await;
+
+        // Only echoed, incoming ids aren't trusted
+        assert_ne!(uuid, "a1a2a3a4-b1b2-c1c2-d1d2-d3d4d5d6d7d8");
+        assert_eq!(res["X-Request-Id"].as_str(), uuid);
+        assert_eq!(res.body_string().await.unwrap(), uuid);
+    }
+
+    #[async_std::test]
+    async fn middleware_fields() {
+        let mut app = tide::new();
+        app.with(TreeMiddleware::new("stdout"));
+        app.at("/users/:id")
+            .with(MatchedRoute("/users/:id"))
+            .post(|_| async { Ok("created") });
+
+        let request = || {
+            let url = Url::parse("http://localhost/users/42?token=hunter2").unwrap();
+            let mut req = Request::new(Method::Post, url);
+            req.set_peer_addr(Some("10.0.0.1:4242"));
+            req.set_version(Some(tide::http::Version::Http1_1));
+            req.insert_header("User-Agent", "curl/7.68.0");
+            req.insert_header("Authorization", "Bearer hunter2");
+            req.insert_header("Cookie", "session=hunter2");
+            req.set_body("{}");
+            req
+        };
+
+        let (_, root) = tide_request_root(&app, request()).await;
+        let values = root["values"].as_object().unwrap();
+        assert_eq!(values["http.remote_addr"], "10.0.0.1:4242");
+        assert_eq!(values["http.user_agent"], "curl/7.68.0");
+        assert_eq!(values["http.version"], "HTTP/1.1");
+        assert_eq!(values["http.request_size"], "2");
+        assert_eq!(values["http.route"], "/users/:id");
+        assert_eq!(values["http.response_size"], "7");
+        assert!(values.contains_key("http.latency_us"));
+        assert!(!values.contains_key("http.query"));
+        assert!(!root.to_string().contains("hunter2"));
+
+        let mut app = tide::new();
+        app.with(TreeMiddleware::new("stdout").with_fields(RequestFields::none().query(true)));
+        app.at("/users/:id").post(|_| async { Ok("created") });
+
+        let (_, root) = tide_request_root(&app, request()).await;
+        let values = root["values"].as_object().unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values["http.query"], "token=hunter2");
+    }
+
+    #[async_std::test]
+    async fn middleware_config() {
+        let mut app = tide::new();
+        app.with(
+            TreeMiddleware::new("stdout").with_config(|req| match req.url().path() {
+                "/health" => RequestConfig::discard(),
+                path if path.starts_with("/admin/") => {
+                    RequestConfig::new("test_out/audit.log").max_level(LevelFilter::INFO)
+                }
+                _ => RequestConfig::default().sampled(req.header("X-Sampled").is_some()),
+            }),
+        );
+        app.at("/health").get(|_| async { Ok("ok") });
+        app.at("/admin/*").get(|_| async {
+            admin_info!("Admin action");
+            debug!("Too verbose for the audit log");
+            Ok("done")
+        });
+        app.at("/").get(|_| async { Ok("Hello, world!") });
+
+        let respond = |path: &str, sampled: bool| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = Request::new(Method::Get, url);
+            if sampled {
+                req.insert_header("X-Sampled", "1");
+            }
+
+            let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+            let guard = tracing::subscriber::set_default(TreeSubscriber::pretty(log_tx));
+            let app = app.clone();
+            async move {
+                let _: Response = app.respond(req).await.unwrap();
+                drop(guard);
+                match log_rx.recv().ok()?.processed() {
+                    TreeProcessed::Span(span) => Some(span),
+                    TreeProcessed::Event(_) => panic!("Expected a tree"),
+                }
+            }
+        };
+
+        // Discarded requests don't even send their tree
+        assert!(respond("/health", true).await.is_none());
+
+        let admin = respond("/admin/users", true).await.unwrap();
+        assert!(matches!(admin.out, TreeIo::File(ref path) if path == "test_out/audit.log"));
+        let messages = admin
+            .processed_buf
+            .iter()
+            .filter_map(|logs| match logs {
+                TreeProcessed::Event(event) => Some(event.message.as_str()),
+                TreeProcessed::Span(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            ["Request received", "Admin action", "--> Response sent"]
+        );
+
+        // Without an output of its own, the middleware's is used
+        assert!(respond("/", false).await.is_none());
+        assert!(matches!(
+            respond("/", true).await.unwrap().out,
+            TreeIo::Stdout
+        ));
+    }
+
+    #[tokio::test]
+    async fn middleware_test() {
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+
+        let subscriber = TreeSubscriber::pretty(log_tx);
+        tracing::subscriber::set_global_default(subscriber).unwrap();
+
+        std::thread::spawn(move || {
+            for processor in log_rx {
+                processor.process().expect("Write failed");
+            }
+        });
+
+        let mut app = tide::new();
+        app.with(TreeMiddleware::new("test_out/middleware_test.log"));
+        app.at("/").get(|_| async { Ok("Hello, world!") });
+        app.at("/sara").get(|_| async { Ok("omg it's sara!") });
+        app.listen("127.0.0.1:8080").await.unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use http::header::HeaderValue;
+use http::{Request, Response};
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::{self, trace_span, Instrument};
+
+use super::{log_response, RequestId, TraceContext, TRACEPARENT, TRACESTATE};
+
+// `tower::Layer` equivalent of `TreeMiddleware`, for axum and hyper services.
+
+#[derive(Clone, Copy, Debug)]
+pub struct TowerTreeMiddleware {
+    output: &'static str,
+}
+
+#[derive(Clone, Debug)]
+pub struct TowerTreeService<S> {
+    inner: S,
+    output: &'static str,
+}
+
+impl TowerTreeMiddleware {
+    pub fn new(output: &'static str) -> Self {
+        TowerTreeMiddleware { output }
+    }
+}
+
+impl<S> Layer<S> for TowerTreeMiddleware {
+    type Service = TowerTreeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TowerTreeService {
+            inner,
+            output: self.output,
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TowerTreeService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: fmt::Display,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // Already logging into a tree further up the stack
+        if req.extensions().get::<RequestId>().is_some() {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+
+        let trace = TraceContext::new(header(TRACEPARENT), header(TRACESTATE), None);
+        let uuid = trace.uuid;
+        req.extensions_mut().insert(RequestId(uuid));
+
+        let span = trace_span!("tower-request", %uuid, output = self.output);
+
+        let response = span.in_scope(|| {
+            request_info!(
+                http.method = req.method().as_str(),
+                path = req.uri().path(),
+                "Request received"
+            );
+            self.inner.call(req)
+        });
+
+        Box::pin(
+            async move {
+                match response.await {
+                    Ok(mut response) => {
+                        let status = response.status();
+                        log_response(
+                            status.as_u16(),
+                            status.canonical_reason().unwrap_or("?"),
+                            None,
+                        );
+
+                        let headers = response.headers_mut();
+                        if let Ok(traceparent) = HeaderValue::from_str(&trace.traceparent()) {
+                            headers.insert(TRACEPARENT, traceparent);
+                        }
+                        if let Some(tracestate) = trace
+                            .tracestate
+                            .and_then(|tracestate| HeaderValue::from_str(&tracestate).ok())
+                        {
+                            headers.insert(TRACESTATE, tracestate);
+                        }
+
+                        Ok(response)
+                    }
+                    Err(error) => {
+                        request_error!(
+                            message = display(&error),
+                            error_type = std::any::type_name::<S::Error>(),
+                            "Service error -> No response sent"
+                        );
+                        Err(error)
+                    }
+                }
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::TowerTreeMiddleware;
+    use crate::kanidm::KanidmEventTag;
+    use crate::middleware::RequestId;
+    use crate::subscriber::{TreeProcessor, TreeSubscriber};
+    use crate::tests::json_lines;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn tower_middleware() {
+        let service = TowerTreeMiddleware::new("stdout").layer(service_fn(
+            |req: http::Request<()>| async move {
+                let RequestId(uuid) = *req.extensions().get::<RequestId>().unwrap();
+                admin_info!(%uuid, "Handling request");
+                let status = match req.uri().path() {
+                    "/missing" => 404,
+                    _ => 200,
+                };
+                let response = http::Response::builder().status(status).body(()).unwrap();
+                Ok::<_, std::convert::Infallible>(response)
+            },
+        ));
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        let guard = tracing::subscriber::set_default(TreeSubscriber::json(log_tx));
+
+        let req = http::Request::get("/missing")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(())
+            .unwrap();
+        let res = service.oneshot(req).await.unwrap();
+
+        drop(guard);
+
+        assert_eq!(res.status(), 404);
+        let traceparent = res.headers()["traceparent"].to_str().unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+
+        let lines = json_lines(log_rx.recv().unwrap());
+        let uuid = "4bf92f35-77b3-4da6-a3ce-929d0e0e4736";
+        assert_eq!(lines[0]["message"], "tower-request");
+        assert_eq!(lines[0]["uuid"], uuid);
+        assert_eq!(lines[1]["tag"], "request.info");
+        assert_eq!(lines[2]["message"], "Handling request");
+        assert_eq!(lines[2]["values"]["uuid"], uuid);
+        assert_eq!(lines[3]["tag"], "request.warn");
+        assert_eq!(lines[3]["values"]["status"], "404 - Not Found");
+    }
+}
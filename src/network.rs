@@ -0,0 +1,399 @@
+use std::collections::VecDeque;
+use std::io::{self, Write as _};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::formatter::LogFmt;
+use crate::subscriber::{EventTagSet, TreeProcessor};
+
+// Streams JSON lines to a log shipper (Vector, Fluent Bit, Logstash...).
+// Lines are buffered while the endpoint is unreachable, and reconnection
+// is attempted with exponential backoff on the next `send` or `flush`.
+
+const DEFAULT_CAPACITY: usize = 1024;
+const DEFAULT_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// The largest UDP payload over IPv4. Longer lines could never be sent.
+const MAX_DATAGRAM: usize = 65_507;
+
+#[derive(Clone, Debug)]
+pub enum Transport {
+    Tcp,
+    Udp,
+    #[cfg(feature = "tls")]
+    Tls(String),
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+    #[cfg(feature = "tls")]
+    Tls(native_tls::TlsStream<TcpStream>),
+}
+
+pub struct NetworkSink {
+    addr: String,
+    transport: Transport,
+    conn: Option<Connection>,
+    buffer: VecDeque<Vec<u8>>,
+    // Bytes of the front line already written to the connection
+    written: usize,
+    capacity: usize,
+    dropped: u64,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    connect_timeout: Duration,
+}
+
+impl NetworkSink {
+    pub fn new(addr: impl Into<String>, transport: Transport) -> Self {
+        NetworkSink {
+            addr: addr.into(),
+            transport,
+            conn: None,
+            buffer: VecDeque::new(),
+            written: 0,
+            capacity: DEFAULT_CAPACITY,
+            dropped: 0,
+            min_backoff: DEFAULT_MIN_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            backoff: DEFAULT_MIN_BACKOFF,
+            retry_at: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    pub fn tcp(addr: impl Into<String>) -> Self {
+        NetworkSink::new(addr, Transport::Tcp)
+    }
+
+    pub fn udp(addr: impl Into<String>) -> Self {
+        NetworkSink::new(addr, Transport::Udp)
+    }
+
+    // `domain` is the name the server certificate is checked against.
+    #[cfg(feature = "tls")]
+    pub fn tls(addr: impl Into<String>, domain: impl Into<String>) -> Self {
+        NetworkSink::new(addr, Transport::Tls(domain.into()))
+    }
+
+    // Maximum number of lines held while the endpoint is unreachable.
+    // The oldest lines are dropped first.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self.backoff = min;
+        self
+    }
+
+    // How long a single connection attempt may take before it counts as
+    // failed, so an unreachable endpoint doesn't stall the caller.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    // Number of lines discarded because the buffer was full, or because
+    // they don't fit in a UDP datagram.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn send<E: EventTagSet>(&mut self, processor: TreeProcessor<E>) -> io::Result<()> {
+        let formatted_logs = processor.format(LogFmt::Json);
+
+        for line in formatted_logs.split_inclusive(|b| *b == b'\n') {
+            self.buffer_line(line.to_vec());
+        }
+
+        self.flush()
+    }
+
+    // Sends a line that is already formatted, including its newline.
+    pub fn send_line(&mut self, line: Vec<u8>) -> io::Result<()> {
+        self.buffer_line(line);
+        self.flush()
+    }
+
+    fn buffer_line(&mut self, line: Vec<u8>) {
+        let oversized = matches!(self.transport, Transport::Udp)
+            && line.strip_suffix(b"\n").unwrap_or(&line).len() > MAX_DATAGRAM;
+        if self.capacity == 0 || oversized {
+            self.dropped += 1;
+            return;
+        }
+        if self.buffer.len() >= self.capacity {
+            self.buffer.pop_front();
+            self.written = 0;
+            self.dropped += 1;
+        }
+        self.buffer.push_back(line);
+    }
+
+    // Connection failures are not errors: lines stay buffered until a
+    // later `send` or `flush` succeeds in reconnecting.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.buffer.is_empty() {
+            if self.conn.is_none() {
+                match self.connect()? {
+                    Some(conn) => self.conn = Some(conn),
+                    None => return Ok(()),
+                }
+            }
+
+            // A line cut short by a failed write is resumed where it stopped
+            if let (Some(conn), Some(line)) = (self.conn.as_mut(), self.buffer.front()) {
+                if conn.write_line(line, &mut self.written).is_err() {
+                    self.disconnect();
+                    return Ok(());
+                }
+            }
+
+            self.buffer.pop_front();
+            self.written = 0;
+        }
+
+        if let Some(conn) = self.conn.as_mut() {
+            if conn.flush().is_err() {
+                self.disconnect();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn connect(&mut self) -> io::Result<Option<Connection>> {
+        if matches!(self.retry_at, Some(retry_at) if Instant::now() < retry_at) {
+            return Ok(None);
+        }
+
+        let conn = match self.transport {
+            Transport::Tcp => self.tcp_stream().map(Connection::Tcp),
+            Transport::Udp => self.udp_socket().map(Connection::Udp),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref domain) => {
+                let connector = native_tls::TlsConnector::new().map_err(io::Error::other)?;
+                self.tcp_stream().and_then(|stream| {
+                    connector
+                        .connect(domain, stream)
+                        .map(Connection::Tls)
+                        .map_err(|e| io::Error::other(e.to_string()))
+                })
+            }
+        };
+
+        match conn {
+            Ok(conn) => {
+                self.backoff = self.min_backoff;
+                self.retry_at = None;
+                Ok(Some(conn))
+            }
+            Err(_) => {
+                self.schedule_retry();
+                Ok(None)
+            }
+        }
+    }
+
+    fn tcp_stream(&self) -> io::Result<TcpStream> {
+        let mut last_err = io::Error::other("No address to connect to");
+        for addr in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn udp_socket(&self) -> io::Result<UdpSocket> {
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other("No address to send to"))?;
+
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(socket)
+    }
+
+    // The line being written is resent whole on the next connection.
+    fn disconnect(&mut self) {
+        self.conn = None;
+        self.written = 0;
+        self.schedule_retry();
+    }
+
+    fn schedule_retry(&mut self) {
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+    }
+}
+
+impl Connection {
+    // Writes `line` from `written` on, advancing `written` as it goes.
+    fn write_line(&mut self, line: &[u8], written: &mut usize) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => write_from(stream, line, written),
+            // One JSON document per datagram, without the trailing newline
+            Connection::Udp(socket) => {
+                socket.send(line.strip_suffix(b"\n").unwrap_or(line))?;
+                *written = line.len();
+                Ok(())
+            }
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => write_from(stream, line, written),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Udp(_) => Ok(()),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+fn write_from(writer: &mut impl io::Write, line: &[u8], written: &mut usize) -> io::Result<()> {
+    while *written < line.len() {
+        match writer.write(&line[*written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => *written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "kanidm-tags"))]
+mod tests {
+    use super::NetworkSink;
+    use crate::kanidm::KanidmEventTag;
+    use crate::subscriber::{TreeProcessor, TreeSubscriber};
+    use tokio::time::{sleep, Duration};
+    use tracing::trace_span;
+
+    #[tokio::test]
+    async fn network_sink_reconnects() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        // Find a free port, then close it so the first send fails
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut sink = NetworkSink::tcp(addr.to_string())
+            .with_capacity(3)
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(50));
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+
+        let subscriber = TreeSubscriber::pretty(log_tx);
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        trace_span!("first").in_scope(|| request_info!("Not delivered yet"));
+        trace_span!("second").in_scope(|| request_warn!("Still not delivered"));
+
+        drop(guard);
+
+        for processor in log_rx {
+            sink.send(processor).unwrap();
+        }
+
+        // Two lines per tree, so the oldest one was dropped
+        assert_eq!(sink.buffered(), 3);
+        assert_eq!(sink.dropped(), 1);
+
+        // Without a buffer, lines that can't be sent right away are dropped
+        let mut unbuffered = NetworkSink::tcp(addr.to_string()).with_capacity(0);
+        unbuffered.send_line(b"{}\n".to_vec()).unwrap();
+        assert_eq!(unbuffered.buffered(), 0);
+        assert_eq!(unbuffered.dropped(), 1);
+
+        let listener = TcpListener::bind(addr).unwrap();
+        sleep(Duration::from_millis(20)).await;
+        sink.flush().unwrap();
+        assert_eq!(sink.buffered(), 0);
+
+        let (stream, _) = listener.accept().unwrap();
+        let lines = BufReader::new(stream)
+            .lines()
+            .take(3)
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+
+        assert_eq!(lines[0]["message"], "Not delivered yet");
+        assert_eq!(lines[0]["tag"], "request.info");
+        assert_eq!(lines[1]["message"], "second");
+        assert_eq!(lines[1]["log-type"], "span");
+        assert_eq!(lines[2]["message"], "Still not delivered");
+        assert_eq!(lines[2]["level"], "WARN");
+    }
+
+    #[tokio::test]
+    async fn network_sink_udp() {
+        use std::net::UdpSocket;
+
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = NetworkSink::udp(listener.local_addr().unwrap().to_string());
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+
+        let subscriber = TreeSubscriber::pretty(log_tx);
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        trace_span!("udp_root").in_scope(|| admin_info!("Sent over UDP"));
+
+        drop(guard);
+
+        for processor in log_rx {
+            sink.send(processor).unwrap();
+        }
+
+        let mut buf = [0; 1024];
+        let mut recv = || {
+            let len = listener.recv(&mut buf).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&buf[..len]).unwrap()
+        };
+
+        let span = recv();
+        let event = recv();
+        assert_eq!(span["message"], "udp_root");
+        assert_eq!(event["message"], "Sent over UDP");
+        assert_eq!(event["uuid"], span["uuid"]);
+        assert_eq!(event["spans"], serde_json::json!(["udp_root"]));
+
+        // Lines too big for a datagram are dropped, not retried forever
+        let mut line = vec![b'x'; 70_000];
+        line.push(b'\n');
+        sink.send_line(line).unwrap();
+        assert_eq!(sink.dropped(), 1);
+        assert_eq!(sink.buffered(), 0);
+        sink.send_line(b"{}\n".to_vec()).unwrap();
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"{}");
+    }
+}
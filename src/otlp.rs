@@ -0,0 +1,416 @@
+use std::io::{self, BufRead, BufReader, Write as _};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tracing::Level;
+use uuid::Uuid;
+
+use crate::subscriber::{EventTagSet, TreeEvent, TreeProcessed, TreeProcessor, TreeSpanProcessed};
+
+// Exports processed trees as OTLP spans and logs, using the JSON encoding
+// of OTLP/HTTP. Each root tree is one trace whose id is the root uuid.
+
+const TRACES_PATH: &str = "/v1/traces";
+const LOGS_PATH: &str = "/v1/logs";
+
+// `SPAN_KIND_INTERNAL`
+const SPAN_KIND: u8 = 1;
+
+// `STATUS_CODE_UNSET` and `STATUS_CODE_ERROR`
+const STATUS_UNSET: u8 = 0;
+const STATUS_ERROR: u8 = 2;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct OtlpExporter {
+    endpoint: String,
+    service_name: String,
+    timeout: Duration,
+}
+
+#[derive(Default)]
+struct Export {
+    spans: Vec<Value>,
+    logs: Vec<Value>,
+}
+
+impl OtlpExporter {
+    // `endpoint` is the collector's base url, e.g. `http://127.0.0.1:4318`.
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        OtlpExporter {
+            endpoint: endpoint.into(),
+            service_name: "unknown_service".to_string(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_service_name(mut self, service_name: impl Into<String>) -> Self {
+        self.service_name = service_name.into();
+        self
+    }
+
+    // Applies to connecting to the collector, and to each write and read.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn export<E: EventTagSet>(&self, processor: TreeProcessor<E>) -> io::Result<()> {
+        let mut export = Export::default();
+
+        match processor.processed() {
+            TreeProcessed::Event(event) => export.logs.push(log_record(&event, None)),
+            TreeProcessed::Span(span) => {
+                let trace_id = trace_id(span.uuid.as_deref());
+                export.span(&span, &trace_id, None);
+            }
+        }
+
+        if !export.spans.is_empty() {
+            let body = json!({
+                "resourceSpans": [{
+                    "resource": self.resource(),
+                    "scopeSpans": [{ "scope": scope(), "spans": export.spans }],
+                }]
+            });
+            self.post(TRACES_PATH, &body)?;
+        }
+
+        if !export.logs.is_empty() {
+            let body = json!({
+                "resourceLogs": [{
+                    "resource": self.resource(),
+                    "scopeLogs": [{ "scope": scope(), "logRecords": export.logs }],
+                }]
+            });
+            self.post(LOGS_PATH, &body)?;
+        }
+
+        Ok(())
+    }
+
+    fn resource(&self) -> Value {
+        json!({ "attributes": [attribute("service.name", &self.service_name)] })
+    }
+
+    fn post(&self, path: &str, body: &Value) -> io::Result<()> {
+        if self.endpoint.starts_with("https://") {
+            return Err(io::Error::other(
+                "OTLP over https:// is not supported, export to a local collector over http://",
+            ));
+        }
+
+        let authority = self
+            .endpoint
+            .strip_prefix("http://")
+            .ok_or_else(|| io::Error::other("Only http:// OTLP endpoints are supported"))?
+            .trim_end_matches('/');
+
+        let (host, base) = match authority.find('/') {
+            Some(i) => authority.split_at(i),
+            None => (authority, ""),
+        };
+
+        let body = serde_json::to_vec(body)?;
+
+        let mut stream = self.connect(host)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        write!(
+            stream,
+            "POST {}{} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            base,
+            path,
+            host,
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "OTLP export to {} failed: {}",
+                path,
+                status_line.trim_end()
+            ))),
+        }
+    }
+
+    fn connect(&self, host: &str) -> io::Result<TcpStream> {
+        let mut last_err = io::Error::other(format!("No address for {}", host));
+        for addr in host.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+impl Export {
+    fn span<E: EventTagSet>(
+        &mut self,
+        span: &TreeSpanProcessed<E>,
+        trace_id: &str,
+        parent_span_id: Option<&str>,
+    ) {
+        let span_id = span_id();
+        let mut events = vec![];
+        let mut status = STATUS_UNSET;
+
+        for logs in span.processed_buf.iter() {
+            match logs {
+                TreeProcessed::Event(event) => {
+                    if event.level == Level::ERROR {
+                        status = STATUS_ERROR;
+                    }
+                    events.push(span_event(event));
+                    self.logs
+                        .push(log_record(event, Some((trace_id, span_id.as_str()))));
+                }
+                TreeProcessed::Span(child) => self.span(child, trace_id, Some(&span_id)),
+            }
+        }
+
+        let start = unix_nanos(span.timestamp);
+        let end = unix_nanos(span.closed);
+
+        let mut attributes = vec![
+            attribute("nanos.nested", &span.nested_duration.to_string()),
+            attribute("nanos.total", &span.total_duration.to_string()),
+        ];
+        for (key, value) in span.values.iter() {
+            attributes.push(attribute(key, value));
+        }
+
+        self.spans.push(json!({
+            "traceId": trace_id,
+            "spanId": span_id,
+            "parentSpanId": parent_span_id.unwrap_or(""),
+            "name": span.name,
+            "kind": SPAN_KIND,
+            "startTimeUnixNano": start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+            "attributes": attributes,
+            "events": events,
+            "status": { "code": status },
+        }));
+    }
+}
+
+fn span_event<E: EventTagSet>(event: &TreeEvent<E>) -> Value {
+    json!({
+        "timeUnixNano": unix_nanos(event.timestamp).to_string(),
+        "name": event.message,
+        "attributes": event_attributes(event),
+    })
+}
+
+fn log_record<E: EventTagSet>(event: &TreeEvent<E>, ids: Option<(&str, &str)>) -> Value {
+    let mut record = json!({
+        "timeUnixNano": unix_nanos(event.timestamp).to_string(),
+        "severityNumber": severity_number(event.level),
+        "severityText": event.level.as_str(),
+        "body": { "stringValue": event.message },
+        "attributes": event_attributes(event),
+    });
+
+    if let Some((trace_id, span_id)) = ids {
+        record["traceId"] = trace_id.into();
+        record["spanId"] = span_id.into();
+    }
+
+    record
+}
+
+fn event_attributes<E: EventTagSet>(event: &TreeEvent<E>) -> Vec<Value> {
+    let mut attributes = vec![attribute("level", event.level.as_str())];
+
+    if let Some(tag) = event.tag {
+        attributes.push(attribute("tag", tag.pretty()));
+    }
+
+    for (key, value) in event.values.iter() {
+        attributes.push(attribute(key, value));
+    }
+
+    attributes
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn scope() -> Value {
+    json!({ "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") })
+}
+
+fn severity_number(level: Level) -> u8 {
+    match level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+fn unix_nanos(timestamp: SystemTime) -> u128 {
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+// The root uuid is the trace id. Uuids that were provided through a
+// `uuid` field but don't parse get a fresh trace id instead.
+fn trace_id(uuid: Option<&str>) -> String {
+    let trace_id = uuid
+        .map(|uuid| uuid.replace('-', ""))
+        .filter(|hex| hex.len() == 32)
+        .and_then(|hex| u128::from_str_radix(&hex, 16).ok())
+        .unwrap_or_else(|| Uuid::new_v4().as_u128());
+    format!("{:032x}", trace_id)
+}
+
+fn span_id() -> String {
+    format!("{:016x}", Uuid::new_v4().as_u128() as u64)
+}
+
+#[cfg(all(test, feature = "kanidm-tags"))]
+mod tests {
+    use super::OtlpExporter;
+    use crate::kanidm::KanidmEventTag;
+    use crate::subscriber::{TreeProcessor, TreeSubscriber};
+    use std::time::Duration;
+    use tracing::trace_span;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn otlp_export() {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+
+        // Collector stand-in: answers each request with 200 and hands back
+        // the request path and body.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for stream in listener.incoming().take(2) {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split_whitespace().nth(1).unwrap().to_string();
+
+                let mut content_length = 0;
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(len) = line.strip_prefix("Content-Length: ") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                }
+
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                reader
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                requests.push((path, body));
+            }
+            requests
+        });
+
+        let exporter = OtlpExporter::new(endpoint).with_service_name("otlp-test");
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+
+        let subscriber = TreeSubscriber::pretty(log_tx);
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let uuid = Uuid::new_v4();
+        let root = trace_span!("otlp_root", %uuid);
+        root.in_scope(|| {
+            request_info!("Request received");
+            trace_span!("otlp_child").in_scope(|| filter_error!(code = 7, "Filter failed"));
+        });
+        // Idle time counts towards the span's length
+        std::thread::sleep(Duration::from_millis(20));
+        drop(root);
+
+        drop(guard);
+
+        for processor in log_rx {
+            exporter.export(processor).unwrap();
+        }
+
+        let requests = collector.join().unwrap();
+
+        let (path, traces) = &requests[0];
+        assert_eq!(path, "/v1/traces");
+        let resource_spans = &traces["resourceSpans"][0];
+        assert_eq!(
+            resource_spans["resource"]["attributes"][0]["value"]["stringValue"],
+            "otlp-test"
+        );
+
+        // Children are exported before their parents
+        let spans = resource_spans["scopeSpans"][0]["spans"].as_array().unwrap();
+        let (child, root) = (&spans[0], &spans[1]);
+        let trace_id = uuid.to_simple().to_string();
+        assert_eq!(root["name"], "otlp_root");
+        assert_eq!(root["traceId"], trace_id.as_str());
+        assert_eq!(root["parentSpanId"], "");
+        assert_eq!(root["events"][0]["name"], "Request received");
+        assert_eq!(root["status"]["code"], 0);
+        let nanos = |span: &serde_json::Value, key: &str| {
+            span[key].as_str().unwrap().parse::<u128>().unwrap()
+        };
+        assert!(nanos(root, "endTimeUnixNano") - nanos(root, "startTimeUnixNano") >= 20_000_000);
+        assert!(nanos(child, "endTimeUnixNano") <= nanos(root, "endTimeUnixNano"));
+        assert_eq!(child["name"], "otlp_child");
+        assert_eq!(child["traceId"], trace_id.as_str());
+        assert_eq!(child["parentSpanId"], root["spanId"]);
+        assert_ne!(child["spanId"], root["spanId"]);
+        assert_eq!(child["events"][0]["name"], "Filter failed");
+        assert_eq!(child["status"]["code"], 2);
+
+        let (path, logs) = &requests[1];
+        assert_eq!(path, "/v1/logs");
+        let records = logs["resourceLogs"][0]["scopeLogs"][0]["logRecords"]
+            .as_array()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["severityText"], "INFO");
+        assert_eq!(records[0]["traceId"], trace_id.as_str());
+        assert_eq!(records[1]["body"]["stringValue"], "Filter failed");
+        assert_eq!(records[1]["severityNumber"], 17);
+        assert_eq!(records[1]["spanId"], child["spanId"]);
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+        tracing::subscriber::with_default(TreeSubscriber::pretty(log_tx), || {
+            trace_span!("otlp_https").in_scope(|| request_info!("Not exported"));
+        });
+        let exporter = OtlpExporter::new("https://127.0.0.1:4318");
+        let error = exporter.export(log_rx.recv().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("https:// is not supported"));
+    }
+}
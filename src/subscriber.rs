@@ -1,47 +1,122 @@
+use std::any::TypeId;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::error::Error;
 use std::fmt::{self, Write as _};
 use std::fs::OpenOptions;
-use std::io::{self, Write as _};
-use std::time::Duration;
+use std::io::{self, IsTerminal, Write as _};
+use std::sync::mpsc::{Sender, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use chrono::{DateTime, Utc};
+#[cfg(feature = "tokio-worker")]
 use tokio::sync::mpsc::UnboundedSender;
 use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
 use tracing::span::{Attributes, Record};
 use tracing::{Event, Id, Level, Metadata, Subscriber};
+use tracing_core::span::Current;
 use tracing_subscriber::layer::{Context, Layered, SubscriberExt};
-use tracing_subscriber::registry::{Registry, Scope, ScopeFromRoot, SpanRef};
+use tracing_subscriber::registry::{Registry, SpanRef};
 use tracing_subscriber::Layer;
-use uuid::Uuid;
 
-use crate::formatter::LogFmt;
+use crate::alarm::{
+    Alarm, AlarmHandler, AlarmLimit, AlarmLimiter, Escalation, StderrAlarms, Verdict,
+};
+use crate::formatter::{LogFmt, PrettyConfig};
+#[cfg(feature = "log-bridge")]
+use crate::log_bridge::LogTags;
+use crate::syslog::{self, Framing, Severity};
 use crate::timings::Timer;
 
-pub struct TreeSubscriber<E> {
+#[cfg(feature = "derive")]
+pub use tracing_tests_derive::EventTagSet;
+
+pub struct TreeSubscriber<E: 'static> {
     inner: Layered<TreeLayer<E>, Registry>,
 }
 
-struct TreeLayer<E> {
+// Builds a `TreeSubscriber` with more than the default options:
+//
+// TreeLayer::pretty(log_tx).with_threads().with_alarm_limit(limit).build()
+pub struct TreeLayer<E: 'static> {
     fmt: LogFmt,
-    log_tx: UnboundedSender<TreeProcessor<E>>,
+    pretty: Arc<PrettyConfig>,
+    log_tx: Arc<dyn TreeSender<E>>,
+    diagnostics: Option<Sender<Diagnostic>>,
+    // `None` until a handler is added, for the default `StderrAlarms`
+    alarms: Option<Vec<Arc<dyn AlarmHandler<E>>>>,
+    limiter: Option<AlarmLimiter<E>>,
+    escalations: Vec<Escalation<E>>,
+    threads: bool,
+    #[cfg(feature = "log-bridge")]
+    log_tags: Option<Arc<LogTags<E>>>,
+}
+
+// Problems the subscriber can't log itself, reported to the channel given
+// to `TreeLayer::with_diagnostics`.
+#[derive(Clone, Debug)]
+pub enum Diagnostic {
+    // An `event_tag` that the subscriber's `EventTagSet` doesn't know. The
+    // event is still logged untagged, with the raw value kept in its fields.
+    UnknownEventTag {
+        tag: u64,
+        tag_set: &'static str,
+        message: String,
+    },
+    // An alarm handler returned an error. The other handlers still ran.
+    AlarmHandlerFailed {
+        message: String,
+        error: String,
+    },
 }
 
 #[derive(Debug)]
 pub(crate) struct TreeEvent<E> {
-    pub timestamp: DateTime<Utc>,
+    pub timestamp: SystemTime,
     pub message: String,
     pub level: Level,
     pub tag: Option<E>,
+    // Where the event was emitted, from its metadata
+    pub target: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
     pub values: Vec<(&'static str, String)>,
+    // Fields recorded as errors, with the error first and then its sources
+    pub errors: Vec<(&'static str, Vec<String>)>,
+    // Only with `TreeSubscriber::with_threads`
+    pub thread: Option<ThreadInfo>,
+}
+
+// Where an event was emitted or a span entered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub(crate) struct ThreadInfo {
+    pub name: Option<String>,
+    pub id: String,
+    // The tokio task, if any
+    pub task: Option<String>,
 }
 
 #[derive(Debug)]
 struct TreeSpan<E> {
-    pub timestamp: DateTime<Utc>,
+    pub timestamp: SystemTime,
+    // For how long the span was open, which `Timer` doesn't measure
+    pub opened: Instant,
     pub name: &'static str,
     pub buf: Vec<Tree<E>>,
     pub uuid: Option<String>,
     pub out: TreeIo,
+    pub values: Vec<(&'static str, String)>,
+    pub max_level: LevelFilter,
+    // Events per tag in the whole tree, only counted on the root
+    pub tag_counts: HashMap<u64, usize>,
+    // Each distinct thread and task the span was entered on
+    pub threads: Vec<ThreadInfo>,
+    // Set in `on_close`, for sinks that need the span's end time
+    #[cfg(feature = "otlp")]
+    pub closed: Option<SystemTime>,
 }
 
 #[derive(Debug)]
@@ -53,31 +128,181 @@ enum Tree<E> {
 #[derive(Debug)]
 pub struct TreeProcessor<E> {
     fmt: LogFmt,
+    pretty: Arc<PrettyConfig>,
     logs: Tree<E>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum TreeIo {
     Stdout,
     Stderr,
     File(String),
+    Syslog(String),
+    Journald(String),
     Parent,
 }
 
+// Output and level for a span, set from code that creates the span rather
+// than through its fields, see `configure_span`.
+#[derive(Debug)]
+pub(crate) struct SpanConfig {
+    // Only used on root spans
+    pub out: Option<TreeIo>,
+    // `LevelFilter::OFF` drops the whole tree
+    pub max_level: LevelFilter,
+}
+
 pub trait EventTagSet:
     'static + Send + Sync + fmt::Debug + Copy + TryFrom<u64, Error = ()> + Into<u64>
 {
     fn pretty(self) -> &'static str;
 
     fn emoji(self) -> &'static str;
+
+    // The level events with this tag are logged at.
+    fn level(self) -> Level;
+
+    fn category(self) -> TagCategory {
+        TagCategory::Other
+    }
+
+    // Doubles as a rank to compare tags by, lower being more severe.
+    fn severity(self) -> Severity {
+        self.level().into()
+    }
+
+    // Tag sets from different crates should use different namespaces, so
+    // their ids don't collide when combined with `CompositeTag`. The derive
+    // defaults it to the enum name.
+    const NAMESPACE: &'static str = "";
+
+    // What `tagged_event!` records: the id from `Into<u64>` in the lower 32
+    // bits, and a hash of the namespace in the upper 32.
+    fn event_tag(self) -> u64 {
+        namespace_bits(Self::NAMESPACE) | (self.into() & ID_MASK)
+    }
+
+    fn from_event_tag(event_tag: u64) -> Option<Self> {
+        if event_tag & !ID_MASK != namespace_bits(Self::NAMESPACE) {
+            return None;
+        }
+        Self::try_from(event_tag & ID_MASK).ok()
+    }
+}
+
+const ID_MASK: u64 = 0xffff_ffff;
+
+// FNV-1a, with the empty namespace left at 0.
+fn namespace_bits(namespace: &str) -> u64 {
+    if namespace.is_empty() {
+        return 0;
+    }
+    let hash = namespace.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+    (hash as u64) << 32
+}
+
+// Lets one subscriber log tags from two tag sets, e.g. kanidm's and a
+// library's own. Nest it for more: `CompositeTag<A, CompositeTag<B, C>>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompositeTag<A, B> {
+    First(A),
+    Second(B),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TagCategory {
+    Admin,
+    Request,
+    Security,
+    Filter,
+    Perf,
+    Other,
+}
+
+impl<A: EventTagSet, B: EventTagSet> EventTagSet for CompositeTag<A, B> {
+    fn pretty(self) -> &'static str {
+        match self {
+            CompositeTag::First(tag) => tag.pretty(),
+            CompositeTag::Second(tag) => tag.pretty(),
+        }
+    }
+
+    fn emoji(self) -> &'static str {
+        match self {
+            CompositeTag::First(tag) => tag.emoji(),
+            CompositeTag::Second(tag) => tag.emoji(),
+        }
+    }
+
+    fn level(self) -> Level {
+        match self {
+            CompositeTag::First(tag) => tag.level(),
+            CompositeTag::Second(tag) => tag.level(),
+        }
+    }
+
+    fn category(self) -> TagCategory {
+        match self {
+            CompositeTag::First(tag) => tag.category(),
+            CompositeTag::Second(tag) => tag.category(),
+        }
+    }
+
+    fn severity(self) -> Severity {
+        match self {
+            CompositeTag::First(tag) => tag.severity(),
+            CompositeTag::Second(tag) => tag.severity(),
+        }
+    }
+
+    fn event_tag(self) -> u64 {
+        self.into()
+    }
+
+    fn from_event_tag(event_tag: u64) -> Option<Self> {
+        A::from_event_tag(event_tag)
+            .map(CompositeTag::First)
+            .or_else(|| B::from_event_tag(event_tag).map(CompositeTag::Second))
+    }
+}
+
+// Unlike other tag sets, these convert to and from full event tags, with
+// the namespace of whichever set the tag belongs to.
+impl<A: EventTagSet, B: EventTagSet> From<CompositeTag<A, B>> for u64 {
+    fn from(tag: CompositeTag<A, B>) -> Self {
+        match tag {
+            CompositeTag::First(tag) => tag.event_tag(),
+            CompositeTag::Second(tag) => tag.event_tag(),
+        }
+    }
+}
+
+impl<A: EventTagSet, B: EventTagSet> TryFrom<u64> for CompositeTag<A, B> {
+    type Error = ();
+
+    fn try_from(event_tag: u64) -> Result<Self, Self::Error> {
+        CompositeTag::from_event_tag(event_tag).ok_or(())
+    }
+}
+
+// Hands root trees to whatever processes them, usually a tokio task or a
+// std thread draining the other end of a channel.
+pub trait TreeSender<E>: 'static + Send + Sync {
+    fn send(&self, processor: TreeProcessor<E>) -> io::Result<()>;
 }
 
 pub(crate) struct TreeSpanProcessed<E> {
-    pub timestamp: DateTime<Utc>,
+    pub timestamp: SystemTime,
     pub name: &'static str,
     pub processed_buf: Vec<TreeProcessed<E>>,
     pub uuid: Option<String>,
     pub out: TreeIo,
+    pub values: Vec<(&'static str, String)>,
+    pub threads: Vec<ThreadInfo>,
+    #[cfg(feature = "otlp")]
+    pub closed: SystemTime,
     pub nested_duration: u64,
     pub total_duration: u64,
 }
@@ -89,20 +314,111 @@ pub(crate) enum TreeProcessed<E> {
 
 impl<E: EventTagSet> TreeSubscriber<E> {
     // Only reason this is public is so we can configure at runtime.
-    pub fn new(fmt: LogFmt, log_tx: UnboundedSender<TreeProcessor<E>>) -> Self {
-        TreeSubscriber {
-            inner: Registry::default().with(TreeLayer { fmt, log_tx }),
+    pub fn new(fmt: LogFmt, log_tx: impl TreeSender<E>) -> Self {
+        TreeLayer::new(fmt, log_tx).build()
+    }
+
+    // These are the preferred constructors, see `TreeLayer` for more options.
+
+    #[cfg(feature = "json")]
+    pub fn json(log_tx: impl TreeSender<E>) -> Self {
+        TreeLayer::json(log_tx).build()
+    }
+
+    pub fn pretty(log_tx: impl TreeSender<E>) -> Self {
+        TreeLayer::pretty(log_tx).build()
+    }
+}
+
+impl<E: EventTagSet> TreeLayer<E> {
+    pub fn new(fmt: LogFmt, log_tx: impl TreeSender<E>) -> Self {
+        TreeLayer {
+            fmt,
+            pretty: Arc::new(PrettyConfig::default()),
+            log_tx: Arc::new(log_tx),
+            diagnostics: None,
+            alarms: None,
+            limiter: None,
+            escalations: vec![],
+            threads: false,
+            #[cfg(feature = "log-bridge")]
+            log_tags: None,
         }
     }
 
-    // These are the preferred constructors.
+    #[cfg(feature = "json")]
+    pub fn json(log_tx: impl TreeSender<E>) -> Self {
+        TreeLayer::new(LogFmt::Json, log_tx)
+    }
+
+    pub fn pretty(log_tx: impl TreeSender<E>) -> Self {
+        TreeLayer::new(LogFmt::Pretty, log_tx)
+    }
+
+    // Strict mode, meant for tests: misuse such as unknown event tags is
+    // also sent to `diagnostics`. Failing alarm handlers are only reported
+    // here.
+    pub fn with_diagnostics(mut self, diagnostics: Sender<Diagnostic>) -> Self {
+        self.diagnostics = Some(diagnostics);
+        self
+    }
+
+    // Alarms go to stderr until handlers are added, and then only to them.
+    // Add `StderrAlarms` too to keep it.
+    pub fn with_alarm_handler(mut self, handler: impl AlarmHandler<E>) -> Self {
+        self.alarms
+            .get_or_insert_with(Vec::new)
+            .push(Arc::new(handler));
+        self
+    }
+
+    // Alarms are still checked against escalations and limits, but go
+    // nowhere.
+    pub fn without_alarm_handlers(mut self) -> Self {
+        self.alarms = Some(vec![]);
+        self
+    }
+
+    // Alarms are unlimited by default.
+    pub fn with_alarm_limit(mut self, limit: AlarmLimit) -> Self {
+        self.limiter = Some(AlarmLimiter::new(limit));
+        self
+    }
+
+    // Raise alarms for events or trees matching `rule`, in addition to
+    // those raised with `alarm!`.
+    pub fn with_escalation(mut self, rule: Escalation<E>) -> Self {
+        self.escalations.push(rule);
+        self
+    }
+
+    // Tags for records from the `log` crate, once `log_bridge::init` has
+    // been called.
+    #[cfg(feature = "log-bridge")]
+    pub fn with_log_tags(mut self, tags: LogTags<E>) -> Self {
+        self.log_tags = Some(Arc::new(tags));
+        self
+    }
+
+    // Record the thread, and tokio task if any, of every event and of each
+    // enter of every span.
+    pub fn with_threads(mut self) -> Self {
+        self.threads = true;
+        self
+    }
 
-    pub fn json(log_tx: UnboundedSender<TreeProcessor<E>>) -> Self {
-        TreeSubscriber::new(LogFmt::Json, log_tx)
+    // Only used with `LogFmt::Pretty`.
+    pub fn with_pretty_config(mut self, config: PrettyConfig) -> Self {
+        self.pretty = Arc::new(config);
+        self
     }
 
-    pub fn pretty(log_tx: UnboundedSender<TreeProcessor<E>>) -> Self {
-        TreeSubscriber::new(LogFmt::Pretty, log_tx)
+    pub fn build(mut self) -> TreeSubscriber<E> {
+        let stderr = StderrAlarms::new(self.fmt).with_ascii(self.pretty.ascii());
+        self.alarms.get_or_insert_with(|| vec![Arc::new(stderr)]);
+        TreeSubscriber {
+            inner: Registry::default().with(self),
+        }
     }
 }
 
@@ -146,6 +462,33 @@ impl<E: EventTagSet> Subscriber for TreeSubscriber<E> {
     fn try_close(&self, id: Id) -> bool {
         self.inner.try_close(id)
     }
+
+    fn current_span(&self) -> Current {
+        self.inner.current_span()
+    }
+
+    // Lets `configure_span` reach the registry through a `Dispatch`.
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else {
+            self.inner.downcast_raw(id)
+        }
+    }
+}
+
+// Overrides the fields a span was created with. Takes effect when the span
+// is first entered, so it has to be called before then. Also used by the
+// alarm tests.
+#[cfg(any(feature = "tide-middleware", all(test, feature = "kanidm-tags")))]
+pub(crate) fn configure_span(span: &tracing::Span, config: SpanConfig) {
+    use tracing_subscriber::registry::LookupSpan;
+
+    span.with_subscriber(|(id, dispatch)| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        registry.span(id)?.extensions_mut().insert(config);
+        Some(())
+    });
 }
 
 impl<E: EventTagSet> TreeLayer<E> {
@@ -162,32 +505,115 @@ impl<E: EventTagSet> TreeLayer<E> {
                 .log_tx
                 .send(TreeProcessor {
                     fmt: self.fmt,
+                    pretty: self.pretty.clone(),
                     logs,
                 })
                 .expect("Processing channel has been closed, cannot log events."),
         }
     }
 
-    fn alarm(event: &TreeEvent<E>, maybe_scope: Option<ScopeFromRoot<Registry>>) -> fmt::Result {
-        // This is an emergency and should be sent to the admin immediately
-        // Hence why we are formatting in the working thread
-        let mut writer = event.timestamp.to_rfc3339();
-        write!(writer, " 🚨 [ALARM]")?;
+    fn alarm(&self, alarm: Alarm<E>, callsite: &'static Metadata<'static>) {
+        if let Some(ref limiter) = self.limiter {
+            let Verdict { summaries, send } = limiter.check(callsite, &alarm);
 
-        if let Some(scope) = maybe_scope {
-            for span in scope {
-                write!(writer, "🔹{}", span.name())?;
+            for summary in summaries.iter() {
+                self.send_alarm(summary);
+            }
+            if !send {
+                return;
             }
         }
 
-        write!(writer, ": {}", event.message)?;
+        self.send_alarm(&alarm);
+    }
 
-        for (key, value) in event.values.iter() {
-            write!(writer, " | {}={}", key, value)?;
+    // Checks the escalation rules against an event, counting it towards
+    // the totals of its tree.
+    fn escalate(
+        &self,
+        tree_event: &TreeEvent<E>,
+        event: &Event,
+        ctx: &Context<Registry>,
+    ) -> Option<String> {
+        let tag = tree_event.tag?;
+        if self.escalations.is_empty() {
+            return None;
         }
 
-        eprintln!("{}", writer);
-        Ok(())
+        let count = match ctx
+            .event_scope(event)
+            .and_then(|scope| scope.from_root().next())
+        {
+            Some(root) => {
+                let mut extensions = root.extensions_mut();
+                let root_buf = extensions
+                    .get_mut::<TreeSpan<E>>()
+                    .expect("Span buffer not found, this is a bug");
+                let count = root_buf.tag_counts.entry(tag.event_tag()).or_insert(0);
+                *count += 1;
+                *count
+            }
+            None => 1,
+        };
+
+        self.escalations
+            .iter()
+            .find_map(|rule| rule.triggered_by(tag, count))
+    }
+
+    fn escalate_slow_root(
+        &self,
+        root: &TreeSpan<E>,
+        duration: Duration,
+        callsite: &'static Metadata<'static>,
+    ) {
+        let limit = self.escalations.iter().find_map(|rule| match *rule {
+            Escalation::SlowRoot(limit) if duration > limit => Some(limit),
+            _ => None,
+        });
+
+        if let Some(limit) = limit {
+            let alarm = Alarm {
+                uuid: root.uuid.clone(),
+                timestamp: SystemTime::now(),
+                level: Level::WARN,
+                message: format!("{} took {:?}", root.name, duration),
+                tag: None,
+                values: root.values.clone(),
+                span_path: vec![root.name],
+                escalation: Some(format!("root span over {:?}", limit)),
+            };
+            self.alarm(alarm, callsite);
+        }
+    }
+}
+
+impl<E: 'static> TreeLayer<E> {
+    fn send_alarm(&self, alarm: &Alarm<E>) {
+        // This is an emergency and should be sent to the admin immediately
+        // Hence why the handlers run in the working thread
+        for handler in self.alarms.iter().flatten() {
+            if let (Err(e), Some(diagnostics)) = (handler.alarm(alarm), &self.diagnostics) {
+                let _ = diagnostics.send(Diagnostic::AlarmHandlerFailed {
+                    message: alarm.message.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    fn flush_alarm_summaries(&self, all: bool) {
+        if let Some(ref limiter) = self.limiter {
+            for summary in limiter.flush(all).iter() {
+                self.send_alarm(summary);
+            }
+        }
+    }
+}
+
+impl<E: 'static> Drop for TreeLayer<E> {
+    fn drop(&mut self) {
+        self.flush_alarm_summaries(true);
     }
 }
 
@@ -201,26 +627,29 @@ impl<E: EventTagSet> Layer<Registry> for TreeLayer<E> {
             ctx: &'a Context<'a, Registry>,
             uuid: Option<String>,
             out: TreeIo,
+            values: Vec<(&'static str, String)>,
         }
 
         impl<'a> Visit for Visitor<'a> {
             fn record_str(&mut self, field: &Field, value: &str) {
                 if self.ctx.lookup_current().is_none() && field.name() == "output" {
-                    self.out = match value {
-                        "stdout" => TreeIo::Stdout,
-                        "stderr" => TreeIo::Stderr,
-                        _ => TreeIo::File(value.to_string()),
-                    }
+                    self.out = TreeIo::from_output(value);
+                } else if field.name() == "uuid" {
+                    self.uuid = Some(value.to_string());
                 } else {
-                    self.record_debug(field, &value);
+                    self.record_debug(field, &value)
                 }
             }
 
             fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
-                if field.name() == "uuid" {
-                    let mut buf = String::with_capacity(36);
-                    write!(&mut buf, "{:?}", value).expect("Write failed");
-                    self.uuid = Some(buf);
+                match field.name() {
+                    "uuid" => {
+                        let mut buf = String::with_capacity(36);
+                        write!(&mut buf, "{:?}", value).expect("Write failed");
+                        self.uuid = Some(buf);
+                    }
+                    "output" => {}
+                    name => self.values.push((name, format!("{:?}", value))),
                 }
             }
         }
@@ -229,40 +658,147 @@ impl<E: EventTagSet> Layer<Registry> for TreeLayer<E> {
             ctx: &ctx,
             uuid: None,
             out: TreeIo::Stderr,
+            values: vec![],
         };
 
         attrs.record(&mut v);
 
-        let Visitor { uuid, out, .. } = v;
+        let Visitor {
+            uuid, out, values, ..
+        } = v;
+
+        // Nested spans are filtered like the root they belong to.
+        let max_level = span
+            .parent()
+            .and_then(|parent| Some(parent.extensions().get::<TreeSpan<E>>()?.max_level))
+            .unwrap_or(LevelFilter::TRACE);
 
         // Take provided ID, or make a fresh one if there's no parent span.
-        let uuid = uuid.or_else(|| {
-            ctx.lookup_current()
-                .is_none()
-                .then(|| Uuid::new_v4().to_string())
-        });
+        let uuid = uuid.or_else(|| ctx.lookup_current().is_none().then(root_uuid));
 
         let mut extensions = span.extensions_mut();
 
-        extensions.insert(TreeSpan::<E>::new(name, uuid, out));
+        extensions.insert(TreeSpan::<E>::new(name, uuid, out, values, max_level));
         extensions.insert(Timer::new());
     }
 
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<Registry>) {
+        struct Visitor<'a>(&'a mut Vec<(&'static str, String)>);
+
+        impl<'a> Visitor<'a> {
+            fn record(&mut self, field: &Field, value: String) {
+                // Recording a field again overwrites it
+                match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+                    Some((_, old)) => *old = value,
+                    None => self.0.push((field.name(), value)),
+                }
+            }
+        }
+
+        impl<'a> Visit for Visitor<'a> {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.record(field, format!("{:?}", value))
+            }
+        }
+
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut extensions = span.extensions_mut();
+        let span_buf = extensions
+            .get_mut::<TreeSpan<E>>()
+            .expect("Span buffer not found, this is a bug");
+
+        values.record(&mut Visitor(&mut span_buf.values));
+    }
+
     fn on_event(&self, event: &Event, ctx: Context<Registry>) {
-        let (tree_event, alarm) = TreeEvent::parse(event);
+        let (mut tree_event, alarm, unknown_tag) = TreeEvent::parse(event);
+        if self.threads {
+            tree_event.thread = Some(ThreadInfo::current());
+        }
+        #[cfg(feature = "log-bridge")]
+        let tree_event = crate::log_bridge::bridge(tree_event, event, self.log_tags.as_deref());
 
-        if alarm {
-            let maybe_scope = ctx.event_scope(event).map(Scope::from_root);
-            TreeLayer::alarm(&tree_event, maybe_scope).expect("Alarm failed");
+        if let (Some(tag), Some(diagnostics)) = (unknown_tag, &self.diagnostics) {
+            // Nobody listening is fine, it's only a diagnostic
+            let _ = diagnostics.send(Diagnostic::UnknownEventTag {
+                tag,
+                tag_set: std::any::type_name::<E>(),
+                message: tree_event.message.clone(),
+            });
+        }
+
+        // Filtered events are still alarms, but don't escalate
+        let filtered = ctx.event_span(event).is_some_and(|span| {
+            let extensions = span.extensions();
+            let span_buf = extensions
+                .get::<TreeSpan<E>>()
+                .expect("Span buffer not found, this is a bug");
+            tree_event.level > span_buf.max_level
+        });
+
+        let escalation = if filtered {
+            None
+        } else {
+            self.escalate(&tree_event, event, &ctx)
+        };
+
+        if alarm || escalation.is_some() {
+            let mut uuid = None;
+            let mut span_path = vec![];
+            for span in ctx
+                .event_scope(event)
+                .into_iter()
+                .flat_map(|scope| scope.from_root())
+            {
+                if uuid.is_none() {
+                    uuid = span
+                        .extensions()
+                        .get::<TreeSpan<E>>()
+                        .and_then(|span_buf| span_buf.uuid.clone());
+                }
+                span_path.push(span.name());
+            }
+            let callsite = event.metadata();
+            let alarm = Alarm {
+                // `alarm!` was explicit, so it doesn't need a reason
+                escalation: escalation.filter(|_| !alarm),
+                ..Alarm::new(&tree_event, uuid, span_path)
+            };
+            self.alarm(alarm, callsite);
+        }
+
+        if filtered {
+            return;
         }
 
         self.log_to_parent(Tree::Event(tree_event), ctx.event_span(event));
     }
 
     fn on_enter(&self, id: &Id, ctx: Context<Registry>) {
-        ctx.span(id)
-            .expect("Span not found, this is a bug")
-            .extensions_mut()
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut extensions = span.extensions_mut();
+
+        if let Some(config) = extensions.remove::<SpanConfig>() {
+            let span_buf = extensions
+                .get_mut::<TreeSpan<E>>()
+                .expect("Span buffer not found, this is a bug");
+            if let Some(out) = config.out {
+                span_buf.out = out;
+            }
+            span_buf.max_level = config.max_level;
+        }
+
+        if self.threads {
+            let thread = ThreadInfo::current();
+            let span_buf = extensions
+                .get_mut::<TreeSpan<E>>()
+                .expect("Span buffer not found, this is a bug");
+            if !span_buf.threads.contains(&thread) {
+                span_buf.threads.push(thread);
+            }
+        }
+
+        extensions
             .get_mut::<Timer>()
             .expect("Timer not found, this is a bug")
             .unpause();
@@ -282,15 +818,31 @@ impl<E: EventTagSet> Layer<Registry> for TreeLayer<E> {
 
         let mut extensions = span.extensions_mut();
 
-        let span_buf = extensions
+        #[allow(unused_mut)]
+        let mut span_buf = extensions
             .remove::<TreeSpan<E>>()
             .expect("Span buffer not found, this is a bug");
+        #[cfg(feature = "otlp")]
+        {
+            span_buf.closed = Some(SystemTime::now());
+        }
 
         let duration = extensions
             .remove::<Timer>()
             .expect("Timer not found, this is a bug")
             .duration();
 
+        if span.parent().is_none() {
+            let open_for = span_buf.opened.elapsed();
+            self.escalate_slow_root(&span_buf, open_for, span.metadata());
+            self.flush_alarm_summaries(false);
+        }
+
+        // Unsampled, nothing in the tree is logged
+        if span_buf.max_level == LevelFilter::OFF {
+            return;
+        }
+
         let logs = Tree::Span(span_buf, duration);
 
         self.log_to_parent(logs, span.parent());
@@ -298,23 +850,33 @@ impl<E: EventTagSet> Layer<Registry> for TreeLayer<E> {
 }
 
 impl<E: EventTagSet> TreeEvent<E> {
-    fn parse(event: &Event) -> (Self, bool) {
-        let timestamp = Utc::now();
-        let level = *event.metadata().level();
+    // Also returns whether the event is an alarm, and the raw value of an
+    // `event_tag` that `E` doesn't know.
+    fn parse(event: &Event) -> (Self, bool, Option<u64>) {
+        let timestamp = SystemTime::now();
+        let metadata = event.metadata();
+        let level = *metadata.level();
 
         struct Visitor<TagSet> {
             message: String,
             tag: Option<TagSet>,
             values: Vec<(&'static str, String)>,
+            errors: Vec<(&'static str, Vec<String>)>,
             alarm: bool,
+            unknown_tag: Option<u64>,
         }
 
         impl<TagSet: EventTagSet> Visit for Visitor<TagSet> {
             fn record_u64(&mut self, field: &Field, value: u64) {
                 if field.name() == "event_tag" {
-                    let tag = TagSet::try_from(value)
-                        .unwrap_or_else(|_| panic!("Invalid `event_tag`: {}", value));
-                    self.tag = Some(tag);
+                    match TagSet::from_event_tag(value) {
+                        Some(tag) => self.tag = Some(tag),
+                        // Probably from another tag set, log it untagged
+                        None => {
+                            self.unknown_tag = Some(value);
+                            self.record_debug(field, &value);
+                        }
+                    }
                 } else {
                     self.record_debug(field, &value)
                 }
@@ -336,13 +898,27 @@ impl<E: EventTagSet> TreeEvent<E> {
                     self.values.push((field.name(), format!("{:?}", value)));
                 }
             }
+
+            fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+                let mut chain = vec![value.to_string()];
+                let mut source = value.source();
+                while let Some(error) = source {
+                    chain.push(error.to_string());
+                    source = error.source();
+                }
+
+                self.values.push((field.name(), chain[0].clone()));
+                self.errors.push((field.name(), chain));
+            }
         }
 
         let mut v = Visitor {
             message: String::new(),
             tag: None,
             values: vec![],
+            errors: vec![],
             alarm: false,
+            unknown_tag: None,
         };
 
         event.record(&mut v);
@@ -351,7 +927,9 @@ impl<E: EventTagSet> TreeEvent<E> {
             message,
             tag,
             values,
+            errors,
             alarm,
+            unknown_tag,
         } = v;
 
         (
@@ -360,21 +938,101 @@ impl<E: EventTagSet> TreeEvent<E> {
                 message,
                 level,
                 tag,
+                target: metadata.target().to_string(),
+                module_path: metadata.module_path().map(str::to_string),
+                file: metadata.file().map(str::to_string),
+                line: metadata.line(),
                 values,
+                errors,
+                thread: None,
             },
             alarm,
+            unknown_tag,
         )
     }
 }
 
+impl ThreadInfo {
+    fn current() -> Self {
+        let thread = std::thread::current();
+        // `ThreadId(3)`, there's no stable way to get the number itself
+        let id = format!("{:?}", thread.id());
+        let id = id
+            .trim_start_matches("ThreadId(")
+            .trim_end_matches(')')
+            .to_string();
+
+        #[cfg(feature = "tokio-worker")]
+        let task = tokio::task::try_id().map(|id| id.to_string());
+        #[cfg(not(feature = "tokio-worker"))]
+        let task = None;
+
+        ThreadInfo {
+            name: thread.name().map(str::to_string),
+            id,
+            task,
+        }
+    }
+}
+
+// `main (1)`, `tokio-runtime-worker (7) task 12`
+impl fmt::Display for ThreadInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({})",
+            self.name.as_deref().unwrap_or("unnamed"),
+            self.id
+        )?;
+        if let Some(ref task) = self.task {
+            write!(f, " task {}", task)?;
+        }
+        Ok(())
+    }
+}
+
+impl TreeIo {
+    // From an `output` field or `TreeMiddleware` output.
+    pub(crate) fn from_output(output: &str) -> Self {
+        match output {
+            "stdout" => TreeIo::Stdout,
+            "stderr" => TreeIo::Stderr,
+            "syslog" => TreeIo::Syslog(syslog::SYSLOG_SOCKET.to_string()),
+            "journald" => TreeIo::Journald(syslog::JOURNALD_SOCKET.to_string()),
+            _ => {
+                if let Some(path) = output.strip_prefix("syslog:") {
+                    TreeIo::Syslog(path.to_string())
+                } else if let Some(path) = output.strip_prefix("journald:") {
+                    TreeIo::Journald(path.to_string())
+                } else {
+                    TreeIo::File(output.to_string())
+                }
+            }
+        }
+    }
+}
+
 impl<E> TreeSpan<E> {
-    fn new(name: &'static str, uuid: Option<String>, out: TreeIo) -> Self {
+    fn new(
+        name: &'static str,
+        uuid: Option<String>,
+        out: TreeIo,
+        values: Vec<(&'static str, String)>,
+        max_level: LevelFilter,
+    ) -> Self {
         TreeSpan {
-            timestamp: Utc::now(),
+            timestamp: SystemTime::now(),
+            opened: Instant::now(),
             name,
             buf: vec![],
             uuid,
             out,
+            values,
+            max_level,
+            tag_counts: HashMap::new(),
+            threads: vec![],
+            #[cfg(feature = "otlp")]
+            closed: None,
         }
     }
 
@@ -414,6 +1072,10 @@ impl<E: EventTagSet> Tree<E> {
                     processed_buf,
                     uuid: span_buf.uuid,
                     out: span_buf.out,
+                    values: span_buf.values,
+                    threads: span_buf.threads,
+                    #[cfg(feature = "otlp")]
+                    closed: span_buf.closed.expect("Span not closed, this is a bug"),
                     nested_duration,
                     total_duration: duration.as_nanos() as u64,
                 })
@@ -422,26 +1084,97 @@ impl<E: EventTagSet> Tree<E> {
     }
 }
 
+impl<E: EventTagSet> TreeSender<E> for Sender<TreeProcessor<E>> {
+    fn send(&self, processor: TreeProcessor<E>) -> io::Result<()> {
+        Sender::send(self, processor).map_err(|_| closed())
+    }
+}
+
+impl<E: EventTagSet> TreeSender<E> for SyncSender<TreeProcessor<E>> {
+    fn send(&self, processor: TreeProcessor<E>) -> io::Result<()> {
+        SyncSender::send(self, processor).map_err(|_| closed())
+    }
+}
+
+#[cfg(feature = "tokio-worker")]
+impl<E: EventTagSet> TreeSender<E> for UnboundedSender<TreeProcessor<E>> {
+    fn send(&self, processor: TreeProcessor<E>) -> io::Result<()> {
+        UnboundedSender::send(self, processor).map_err(|_| closed())
+    }
+}
+
 impl<E: EventTagSet> TreeProcessor<E> {
+    // Formats regardless of the format the subscriber was configured with,
+    // for sinks that require a specific one.
+    #[cfg(any(feature = "json", all(test, feature = "kanidm-tags")))]
+    pub(crate) fn format(self, fmt: LogFmt) -> Vec<u8> {
+        let pretty = self.pretty.clone();
+        fmt.format(&self.processed(), &pretty, false)
+    }
+
+    #[cfg(any(feature = "json", all(test, feature = "kanidm-tags")))]
+    pub(crate) fn processed(self) -> TreeProcessed<E> {
+        self.logs.process()
+    }
+
     pub fn process(self) -> io::Result<()> {
         let processed_logs = self.logs.process();
-        let formatted_logs = self.fmt.format(&processed_logs);
-
-        let buf = &formatted_logs[..];
-
-        match processed_logs {
-            TreeProcessed::Event(_) => io::stderr().write_all(buf),
-            TreeProcessed::Span(TreeSpanProcessed { out, .. }) => match out {
-                TreeIo::Stdout | TreeIo::Parent => io::stdout().write_all(buf),
-                TreeIo::Stderr => io::stderr().write_all(buf),
-                TreeIo::File(path) => OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .write(true)
-                    .open(&path)
-                    .unwrap_or_else(|_| panic!("Failed to open file: {}", path))
-                    .write_all(buf),
-            },
+
+        let out = match processed_logs {
+            TreeProcessed::Event(_) => &TreeIo::Stderr,
+            TreeProcessed::Span(TreeSpanProcessed { ref out, .. }) => out,
+        };
+
+        let fmt = self.fmt;
+        let pretty = &self.pretty;
+        let color = |is_terminal| pretty.color(is_terminal);
+        let format = |color| fmt.format(&processed_logs, pretty, color);
+
+        match out {
+            TreeIo::Stdout | TreeIo::Parent => {
+                let stdout = io::stdout();
+                stdout
+                    .lock()
+                    .write_all(&format(color(stdout.is_terminal())))
+            }
+            TreeIo::Stderr => {
+                let stderr = io::stderr();
+                stderr
+                    .lock()
+                    .write_all(&format(color(stderr.is_terminal())))
+            }
+            TreeIo::File(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|_| panic!("Failed to open file: {}", path))
+                .write_all(&format(color(false))),
+            TreeIo::Syslog(path) => syslog::send(path, Framing::Rfc5424, &processed_logs),
+            TreeIo::Journald(path) => syslog::send(path, Framing::Journald, &processed_logs),
         }
     }
 }
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "Processing channel has been closed",
+    )
+}
+
+// For root spans without a `uuid` field.
+#[cfg(feature = "uuid")]
+fn root_uuid() -> String {
+    uuid::Uuid::new_v4().to_hyphenated().to_string()
+}
+
+// Without the `uuid` feature, roots are numbered instead, with the process
+// id in the last group. These are only unique within the process.
+#[cfg(not(feature = "uuid"))]
+fn root_uuid() -> String {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static NEXT: AtomicU32 = AtomicU32::new(1);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!("{:08x}-0000-0000-0000-{:012x}", n, std::process::id())
+}
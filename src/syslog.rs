@@ -0,0 +1,424 @@
+use std::fmt::Write as _;
+use std::io;
+
+use tracing::Level;
+
+use crate::subscriber::{EventTagSet, TreeEvent, TreeProcessed, TreeSpanProcessed};
+use crate::timings::Rfc3339;
+
+// Syslog and journald sinks. Every event and span in a root tree becomes
+// its own datagram, so the receiving daemon can filter on severity.
+
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// `user-level messages`
+const FACILITY: u8 = 1;
+
+// RFC 5424 reserves this enterprise number for documentation and examples.
+const SD_ID: &str = "tree@32473";
+
+// The params `format_rfc5424` writes itself. User fields by these names get
+// `field_` prefixed instead, as PARAM-NAMEs can't repeat.
+const SD_RESERVED: &[&str] = &["uuid", "level", "tag", "span_path"];
+
+// The fields `format_journald` writes itself, and the ones journald gives a
+// meaning to. User fields by these names get `FIELD_` prefixed instead.
+const JOURNALD_RESERVED: &[&str] = &[
+    "MESSAGE",
+    "MESSAGE_ID",
+    "PRIORITY",
+    "CODE_FILE",
+    "CODE_LINE",
+    "CODE_FUNC",
+    "ERRNO",
+    "SYSLOG_FACILITY",
+    "SYSLOG_IDENTIFIER",
+    "SYSLOG_PID",
+    "SYSLOG_TIMESTAMP",
+    "SYSLOG_RAW",
+    "UUID",
+    "LEVEL",
+    "TAG",
+    "SPAN_PATH",
+    "TIMESTAMP",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Emergency = 0,
+    Alert = 1,
+    Critical = 2,
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Informational = 6,
+    Debug = 7,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Framing {
+    Rfc5424,
+    Journald,
+}
+
+impl From<Level> for Severity {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::ERROR => Severity::Error,
+            Level::WARN => Severity::Warning,
+            Level::INFO => Severity::Informational,
+            Level::DEBUG | Level::TRACE => Severity::Debug,
+        }
+    }
+}
+
+struct Record<'a> {
+    timestamp: String,
+    severity: Severity,
+    level: &'static str,
+    tag: Option<&'static str>,
+    uuid: &'a str,
+    span_path: String,
+    message: &'a str,
+    values: Vec<(&'static str, String)>,
+}
+
+pub(crate) fn send<E: EventTagSet>(
+    path: &str,
+    framing: Framing,
+    processed_logs: &TreeProcessed<E>,
+) -> io::Result<()> {
+    let mut records = vec![];
+    let mut spans = vec![];
+    collect(processed_logs, &mut spans, None, &mut records);
+
+    let app_name = app_name();
+    let datagrams = records.iter().map(|record| match framing {
+        Framing::Rfc5424 => format_rfc5424(record, &app_name),
+        Framing::Journald => format_journald(record, &app_name),
+    });
+
+    send_datagrams(path, datagrams)
+}
+
+#[cfg(unix)]
+fn send_datagrams(path: &str, datagrams: impl Iterator<Item = Vec<u8>>) -> io::Result<()> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    for datagram in datagrams {
+        socket.send(&datagram)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_datagrams(_path: &str, _datagrams: impl Iterator<Item = Vec<u8>>) -> io::Result<()> {
+    Err(io::Error::other(
+        "Unix datagram sockets are not supported on this platform",
+    ))
+}
+
+fn collect<'a, E: EventTagSet>(
+    tree: &'a TreeProcessed<E>,
+    spans: &mut Vec<&'static str>,
+    uuid: Option<&'a str>,
+    records: &mut Vec<Record<'a>>,
+) {
+    match tree {
+        TreeProcessed::Event(event) => records.push(Record::event(event, spans, uuid)),
+        TreeProcessed::Span(span) => {
+            let uuid = span
+                .uuid
+                .as_deref()
+                .or(uuid)
+                .expect("Span has no associated UUID, this is a bug");
+
+            spans.push(span.name);
+            records.push(Record::span(span, spans, uuid));
+            for logs in span.processed_buf.iter() {
+                collect(logs, spans, Some(uuid), records);
+            }
+            spans.pop();
+        }
+    }
+}
+
+impl<'a> Record<'a> {
+    fn event<E: EventTagSet>(
+        event: &'a TreeEvent<E>,
+        spans: &[&str],
+        uuid: Option<&'a str>,
+    ) -> Self {
+        Record {
+            timestamp: Rfc3339(event.timestamp).to_string(),
+            severity: event.tag.map_or_else(|| event.level.into(), E::severity),
+            level: event.level.as_str(),
+            tag: event.tag.map(E::pretty),
+            uuid: uuid.unwrap_or(crate::formatter::EVENT_UUID),
+            span_path: spans.join("/"),
+            message: &event.message,
+            values: event.values.clone(),
+        }
+    }
+
+    fn span<E>(span: &'a TreeSpanProcessed<E>, spans: &[&str], uuid: &'a str) -> Self {
+        Record {
+            timestamp: Rfc3339(span.timestamp).to_string(),
+            severity: Severity::Debug,
+            level: Level::TRACE.as_str(),
+            tag: None,
+            uuid,
+            span_path: spans.join("/"),
+            message: span.name,
+            values: [
+                ("nanos_nested", span.nested_duration.to_string()),
+                ("nanos_total", span.total_duration.to_string()),
+            ]
+            .iter()
+            .chain(span.values.iter())
+            .cloned()
+            .collect(),
+        }
+    }
+
+    fn priority(&self) -> u8 {
+        FACILITY * 8 + self.severity as u8
+    }
+}
+
+fn app_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.file_name()?.to_str().map(str::to_string))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn format_rfc5424(record: &Record, app_name: &str) -> Vec<u8> {
+    // <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID [SD-ELEMENT] MSG
+    let mut buf = format!(
+        "<{}>1 {} - {} {} {} [{} uuid=\"",
+        record.priority(),
+        record.timestamp,
+        app_name,
+        std::process::id(),
+        record.tag.unwrap_or("-"),
+        SD_ID,
+    );
+
+    escape_param_value(&mut buf, record.uuid);
+    buf.push_str("\" level=\"");
+    escape_param_value(&mut buf, record.level);
+    buf.push('"');
+    if let Some(tag) = record.tag {
+        buf.push_str(" tag=\"");
+        escape_param_value(&mut buf, tag);
+        buf.push('"');
+    }
+    buf.push_str(" span_path=\"");
+    escape_param_value(&mut buf, &record.span_path);
+    buf.push('"');
+
+    for (key, value) in record.values.iter() {
+        buf.push(' ');
+        buf.push_str(&sd_param_name(key));
+        buf.push_str("=\"");
+        escape_param_value(&mut buf, value);
+        buf.push('"');
+    }
+
+    write!(buf, "] {}", record.message).expect("Write failed");
+    buf.into_bytes()
+}
+
+fn sd_param_name(key: &str) -> String {
+    // PARAM-NAME is printable US-ASCII except '=', ' ', ']' and '"', at
+    // most 32 characters.
+    let name: String = key
+        .chars()
+        .filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"'))
+        .take(32)
+        .collect();
+
+    if SD_RESERVED.contains(&name.as_str()) {
+        format!("field_{}", name)
+    } else {
+        name
+    }
+}
+
+fn escape_param_value(buf: &mut String, value: &str) {
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            buf.push('\\');
+        }
+        buf.push(c);
+    }
+}
+
+fn format_journald(record: &Record, app_name: &str) -> Vec<u8> {
+    let mut buf = vec![];
+
+    journald_field(&mut buf, "MESSAGE", record.message);
+    journald_field(&mut buf, "PRIORITY", &(record.severity as u8).to_string());
+    journald_field(&mut buf, "SYSLOG_FACILITY", &FACILITY.to_string());
+    journald_field(&mut buf, "SYSLOG_IDENTIFIER", app_name);
+    journald_field(&mut buf, "UUID", record.uuid);
+    journald_field(&mut buf, "LEVEL", record.level);
+    if let Some(tag) = record.tag {
+        journald_field(&mut buf, "TAG", tag);
+    }
+    journald_field(&mut buf, "SPAN_PATH", &record.span_path);
+    journald_field(&mut buf, "TIMESTAMP", &record.timestamp);
+
+    for (key, value) in record.values.iter() {
+        journald_field(&mut buf, &journald_name(key), value);
+    }
+
+    buf
+}
+
+fn journald_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Multi-line values use the binary framing: NAME\n<u64 le length><value>\n
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+fn journald_name(key: &str) -> String {
+    // Journald field names are uppercase ASCII, digits and underscores,
+    // and must not start with an underscore or a digit.
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+
+    match name.chars().next() {
+        Some('A'..='Z') if JOURNALD_RESERVED.contains(&name.as_str()) => format!("FIELD_{}", name),
+        Some('A'..='Z') => name,
+        _ => format!("F{}", name),
+    }
+}
+
+#[cfg(all(test, feature = "kanidm-tags"))]
+mod tests {
+    use super::Severity;
+    use crate::kanidm::KanidmEventTag;
+    use crate::subscriber::{CompositeTag, EventTagSet};
+    #[cfg(unix)]
+    use crate::subscriber::{TreeProcessor, TreeSubscriber};
+    #[cfg(unix)]
+    use tracing::trace_span;
+
+    #[test]
+    fn severity() {
+        let tag = KanidmEventTag::SecurityCritical;
+
+        // Security tags are raised above errors that aren't security related
+        let severity = KanidmEventTag::severity;
+        assert_eq!(severity(tag), Severity::Critical);
+        assert!(severity(tag) < severity(KanidmEventTag::AdminError));
+        assert_eq!(severity(KanidmEventTag::SecurityInfo), Severity::Notice);
+        assert_eq!(severity(KanidmEventTag::FilterWarn), Severity::Warning);
+
+        let composite = CompositeTag::<KanidmEventTag, KanidmEventTag>::Second(tag);
+        assert_eq!(composite.severity(), Severity::Critical);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn syslog_sink() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("syslog-{}.sock", std::process::id()));
+        let listener = UnixDatagram::bind(&path).unwrap();
+        let output = format!("syslog:{}", path.display());
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+
+        let subscriber = TreeSubscriber::pretty(log_tx);
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        trace_span!("syslog_root", output = output.as_str()).in_scope(|| {
+            admin_error!(user = "sara", level = 3, "An admin error");
+            trace_span!("nested").in_scope(|| security_critical!("A security critical log"));
+        });
+
+        drop(guard);
+
+        for processor in log_rx {
+            processor.process().expect("Write failed");
+        }
+
+        let mut buf = [0; 1024];
+        let mut datagrams = vec![];
+        for _ in 0..4 {
+            let len = listener.recv(&mut buf).unwrap();
+            datagrams.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(datagrams[0].starts_with("<15>1 "));
+        assert!(datagrams[0].contains("span_path=\"syslog_root\""));
+        assert!(datagrams[0].ends_with("] syslog_root"));
+        assert!(datagrams[1].starts_with("<11>1 "));
+        assert!(datagrams[1].contains(" admin.error [tree@32473 "));
+        assert!(datagrams[1].contains("user=\"\\\"sara\\\"\""));
+        // User fields can't repeat the params the sink writes
+        assert!(datagrams[1].contains(" level=\"ERROR\" tag=\"admin.error\" "));
+        assert!(datagrams[1].contains(" field_level=\"3\"]"));
+        assert!(datagrams[1].ends_with("] An admin error"));
+        assert!(datagrams[3].starts_with("<10>1 "));
+        assert!(datagrams[3].contains("span_path=\"syslog_root/nested\""));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn journald_sink() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("journald-{}.sock", std::process::id()));
+        let listener = UnixDatagram::bind(&path).unwrap();
+        let output = format!("journald:{}", path.display());
+
+        let (log_tx, log_rx) = std::sync::mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+
+        let subscriber = TreeSubscriber::pretty(log_tx);
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let uuid = "5b6e5ad2-8c42-4b3c-9d4e-7f1a2b3c4d5e";
+        trace_span!("journald_root", %uuid, output = output.as_str()).in_scope(|| {
+            filter_warn!(attempt = 2, level = 3, "A filter\nwarning");
+        });
+
+        drop(guard);
+
+        for processor in log_rx {
+            processor.process().expect("Write failed");
+        }
+
+        let mut buf = [0; 1024];
+        listener.recv(&mut buf).unwrap();
+        let len = listener.recv(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let datagram = &buf[..len];
+
+        // Multi-line values use the binary framing
+        assert!(datagram.starts_with(b"MESSAGE\n\x10\0\0\0\0\0\0\0A filter\nwarning\nPRIORITY=4\n"));
+
+        let text = String::from_utf8_lossy(datagram);
+        assert!(text.contains(&format!("\nUUID={}\n", uuid)));
+        assert!(text.contains("\nLEVEL=WARN\nTAG=filter.warn\nSPAN_PATH=journald_root\n"));
+        // User fields can't overwrite the ones the sink writes
+        assert!(text.ends_with("\nATTEMPT=2\nFIELD_LEVEL=3\n"));
+    }
+}
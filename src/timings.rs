@@ -1,4 +1,5 @@
-use std::time::{Duration, Instant};
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub struct Timer {
     duration: Duration,
@@ -26,3 +27,78 @@ impl Timer {
         self.duration
     }
 }
+
+// `2021-07-20T18:23:51.125+00:00`, with as many fractional digits as needed
+// in groups of three, like chrono's `to_rfc3339`. Times before the epoch
+// are shown as the epoch.
+pub struct Rfc3339(pub SystemTime);
+
+impl fmt::Display for Rfc3339 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let nanos = since_epoch.subsec_nanos();
+        let (year, month, day) = civil_from_days(secs / 86_400);
+        let secs_of_day = secs % 86_400;
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60
+        )?;
+
+        if nanos == 0 {
+        } else if nanos.is_multiple_of(1_000_000) {
+            write!(f, ".{:03}", nanos / 1_000_000)?;
+        } else if nanos.is_multiple_of(1_000) {
+            write!(f, ".{:06}", nanos / 1_000)?;
+        } else {
+            write!(f, ".{:09}", nanos)?;
+        }
+
+        f.write_str("+00:00")
+    }
+}
+
+// Year, month and day of a number of days since 1970-01-01, from
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rfc3339;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn rfc3339_timestamps() {
+        let at = |secs, nanos| Rfc3339(UNIX_EPOCH + Duration::new(secs, nanos)).to_string();
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00+00:00");
+        assert_eq!(
+            at(1_626_805_431, 125_000_000),
+            "2021-07-20T18:23:51.125+00:00"
+        );
+        assert_eq!(at(1_709_164_799, 1_000), "2024-02-28T23:59:59.000001+00:00");
+        assert_eq!(at(1_709_164_800, 1), "2024-02-29T00:00:00.000000001+00:00");
+    }
+}
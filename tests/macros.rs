@@ -0,0 +1,48 @@
+// The exported macros, used the way other crates use them: by path, and
+// without `tracing` or the tag enum in scope.
+#![cfg(all(feature = "kanidm-tags", feature = "json"))]
+
+use std::sync::mpsc;
+
+use tracing_tests::kanidm::KanidmEventTag;
+use tracing_tests::subscriber::{TreeProcessor, TreeSubscriber};
+
+#[test]
+fn exported_macros() {
+    let path = std::env::temp_dir().join(format!("exported-macros-{}.log", std::process::id()));
+    let output = path.to_str().unwrap();
+
+    let (log_tx, log_rx) = mpsc::channel::<TreeProcessor<KanidmEventTag>>();
+    let subscriber = TreeSubscriber::json(log_tx);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::trace_span!("exported_macros", output).in_scope(|| {
+            tracing_tests::admin_error!(code = 3, "Admin error");
+            tracing_tests::filter_info!("Filter info");
+            tracing_tests::tagged_event!(
+                WARN,
+                tracing_tests::kanidm::KanidmEventTag::RequestWarn,
+                "Tagged by path"
+            );
+        });
+    });
+
+    for processor in log_rx {
+        processor.process().unwrap();
+    }
+
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines = written
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect::<Vec<serde_json::Value>>();
+
+    assert_eq!(lines[0]["message"], "exported_macros");
+    assert_eq!(lines[1]["tag"], "admin.error");
+    assert_eq!(lines[1]["level"], "ERROR");
+    assert_eq!(lines[1]["values"]["code"], "3");
+    assert_eq!(lines[2]["tag"], "filter.info");
+    assert_eq!(lines[2]["level"], "INFO");
+    assert_eq!(lines[3]["tag"], "request.warn");
+    assert_eq!(lines[3]["message"], "Tagged by path");
+}
@@ -0,0 +1,330 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Ident, LitStr, Path, Token,
+};
+
+// `#[derive(EventTagSet)]` for fieldless enums:
+//
+// #[derive(Debug, Clone, Copy, EventTagSet)]
+// #[tag(path = crate::tags::MyTag, namespace = "my_crate")]
+// pub enum MyTag {
+//     #[tag(pretty = "admin.error", emoji = "🚨", level = ERROR, category = Admin)]
+//     AdminError,
+//     #[tag(pretty = "security.critical", emoji = "🔐", level = ERROR, severity = Critical)]
+//     SecurityCritical,
+// }
+//
+// Generates the `EventTagSet` impl, the `u64` conversions (variants are
+// numbered in declaration order) and an exported macro per tag, named
+// after the variant (`admin_error!`) unless `macro_name` is given.
+// `category` defaults to `Other` and `severity` to the one implied by
+// `level`.
+//
+// Variant names are split into words at each capital, keeping runs of
+// capitals together: `HTTPError` gets `http_error!`.
+//
+// `path` is how the generated macros refer to the enum. A leading `crate`
+// becomes `$crate`, so the macros work from other crates. It defaults to
+// the bare enum name, which then has to be in scope wherever the macros
+// are used. `namespace` sets `EventTagSet::NAMESPACE`, and defaults to the
+// enum name so that two derived sets never share one.
+
+#[proc_macro_derive(EventTagSet, attributes(tag))]
+pub fn derive_event_tag_set(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+const LEVELS: &[&str] = &["ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
+enum Value {
+    Str(LitStr),
+    Path(Path),
+}
+
+struct Arg {
+    name: Ident,
+    value: Value,
+}
+
+struct Tag {
+    variant: Ident,
+    pretty: LitStr,
+    emoji: LitStr,
+    level: Ident,
+    category: Option<Ident>,
+    severity: Option<Ident>,
+    macro_name: Ident,
+}
+
+impl Parse for Arg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = if input.peek(LitStr) {
+            Value::Str(input.parse()?)
+        } else {
+            Value::Path(input.parse()?)
+        };
+        Ok(Arg { name, value })
+    }
+}
+
+impl Arg {
+    fn str(self) -> syn::Result<LitStr> {
+        match self.value {
+            Value::Str(lit) => Ok(lit),
+            Value::Path(path) => Err(Error::new(
+                path.span(),
+                format!("`{}` takes a string literal", self.name),
+            )),
+        }
+    }
+
+    fn ident(self) -> syn::Result<Ident> {
+        let name = &self.name;
+        match self.value {
+            Value::Path(path) => path
+                .get_ident()
+                .cloned()
+                .ok_or_else(|| Error::new(path.span(), format!("`{}` takes an identifier", name))),
+            Value::Str(lit) => Err(Error::new(
+                lit.span(),
+                format!("`{}` takes an identifier", name),
+            )),
+        }
+    }
+
+    fn path(self) -> syn::Result<Path> {
+        match self.value {
+            Value::Path(path) => Ok(path),
+            Value::Str(lit) => Err(Error::new(
+                lit.span(),
+                format!("`{}` takes a path", self.name),
+            )),
+        }
+    }
+}
+
+fn tag_args(attrs: &[Attribute]) -> syn::Result<Vec<Arg>> {
+    let mut args = vec![];
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("tag")) {
+        args.extend(attr.parse_args_with(Punctuated::<Arg, Token![,]>::parse_terminated)?);
+    }
+    Ok(args)
+}
+
+fn snake_case(ident: &Ident) -> Ident {
+    let chars = ident.to_string().chars().collect::<Vec<_>>();
+    let mut name = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        // A capital starts a word after a lowercase letter or digit, or
+        // ends a run of capitals when a lowercase letter follows it
+        let prev = i.checked_sub(1).map(|i| chars[i]);
+        let next = chars.get(i + 1);
+        if c.is_uppercase()
+            && (prev.is_some_and(|prev| prev.is_lowercase() || prev.is_ascii_digit())
+                || prev.is_some_and(char::is_uppercase) && next.is_some_and(|c| c.is_lowercase()))
+        {
+            name.push('_');
+        }
+        name.extend(c.to_lowercase());
+    }
+    Ident::new(&name, ident.span())
+}
+
+// `crate::...` as `$crate::...`, for use in exported macros.
+fn macro_path(path: Path) -> TokenStream2 {
+    let mut segments = path.segments.iter();
+    match segments.next() {
+        Some(first) if path.leading_colon.is_none() && first.ident == "crate" => {
+            quote!($crate #(::#segments)*)
+        }
+        _ => quote!(#path),
+    }
+}
+
+impl Tag {
+    fn parse(variant: &syn::Variant) -> syn::Result<Self> {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new(
+                variant.span(),
+                "EventTagSet variants can't have fields",
+            ));
+        }
+
+        let mut pretty = None;
+        let mut emoji = None;
+        let mut level = None;
+        let mut category = None;
+        let mut severity = None;
+        let mut macro_name = None;
+
+        for arg in tag_args(&variant.attrs)? {
+            match arg.name.to_string().as_str() {
+                "pretty" => pretty = Some(arg.str()?),
+                "emoji" => emoji = Some(arg.str()?),
+                "level" => {
+                    let ident = arg.ident()?;
+                    if !LEVELS.contains(&ident.to_string().as_str()) {
+                        return Err(Error::new(
+                            ident.span(),
+                            "Expected one of ERROR, WARN, INFO, DEBUG or TRACE",
+                        ));
+                    }
+                    level = Some(ident);
+                }
+                "category" => category = Some(arg.ident()?),
+                "severity" => severity = Some(arg.ident()?),
+                "macro_name" => macro_name = Some(arg.ident()?),
+                _ => return Err(Error::new(arg.name.span(), "Unknown `tag` attribute")),
+            }
+        }
+
+        let missing = |name| {
+            Error::new(
+                variant.span(),
+                format!("Missing `#[tag({} = ...)]` on {}", name, variant.ident),
+            )
+        };
+
+        Ok(Tag {
+            variant: variant.ident.clone(),
+            pretty: pretty.ok_or_else(|| missing("pretty"))?,
+            emoji: emoji.ok_or_else(|| missing("emoji"))?,
+            level: level.ok_or_else(|| missing("level"))?,
+            category,
+            severity,
+            macro_name: macro_name.unwrap_or_else(|| snake_case(&variant.ident)),
+        })
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+
+    let variants = match input.data {
+        Data::Enum(ref data) => &data.variants,
+        _ => {
+            return Err(Error::new(
+                input.span(),
+                "EventTagSet can only be derived for enums",
+            ))
+        }
+    };
+
+    let mut path = quote!(#name);
+    let mut namespace = LitStr::new(&name.to_string(), name.span());
+    for arg in tag_args(&input.attrs)? {
+        match arg.name.to_string().as_str() {
+            "path" => path = macro_path(arg.path()?),
+            "namespace" => namespace = arg.str()?,
+            _ => return Err(Error::new(arg.name.span(), "Unknown `tag` attribute")),
+        }
+    }
+
+    let tags = variants
+        .iter()
+        .map(Tag::parse)
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let variant = tags.iter().map(|tag| &tag.variant).collect::<Vec<_>>();
+    let pretty = tags.iter().map(|tag| &tag.pretty);
+    let emoji = tags.iter().map(|tag| &tag.emoji);
+    let value = (0..tags.len() as u64).collect::<Vec<_>>();
+
+    let level = tags.iter().map(|tag| &tag.level).collect::<Vec<_>>();
+
+    let category = tags.iter().map(|tag| match tag.category {
+        Some(ref category) => quote!(::tracing_tests::subscriber::TagCategory::#category),
+        None => quote!(::tracing_tests::subscriber::TagCategory::Other),
+    });
+
+    // Without an explicit severity, the one implied by the level
+    let severity = tags.iter().map(|tag| match tag.severity {
+        Some(ref severity) => quote!(::tracing_tests::syslog::Severity::#severity),
+        None => {
+            let level = &tag.level;
+            quote!(::tracing_tests::__private::tracing::Level::#level.into())
+        }
+    });
+
+    let macros = tags.iter().map(|tag| {
+        let Tag {
+            variant,
+            level,
+            macro_name,
+            ..
+        } = tag;
+        quote! {
+            #[macro_export]
+            macro_rules! #macro_name {
+                ($($arg:tt)*) => { ::tracing_tests::tagged_event!(#level, #path::#variant, $($arg)*) }
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl ::tracing_tests::subscriber::EventTagSet for #name {
+            const NAMESPACE: &'static str = #namespace;
+
+            fn pretty(self) -> &'static str {
+                match self {
+                    #(#name::#variant => #pretty,)*
+                }
+            }
+
+            fn emoji(self) -> &'static str {
+                match self {
+                    #(#name::#variant => #emoji,)*
+                }
+            }
+
+            fn level(self) -> ::tracing_tests::__private::tracing::Level {
+                match self {
+                    #(#name::#variant => ::tracing_tests::__private::tracing::Level::#level,)*
+                }
+            }
+
+            fn category(self) -> ::tracing_tests::subscriber::TagCategory {
+                match self {
+                    #(#name::#variant => #category,)*
+                }
+            }
+
+            fn severity(self) -> ::tracing_tests::syslog::Severity {
+                match self {
+                    #(#name::#variant => #severity,)*
+                }
+            }
+        }
+
+        impl ::core::convert::From<#name> for u64 {
+            fn from(tag: #name) -> Self {
+                match tag {
+                    #(#name::#variant => #value,)*
+                }
+            }
+        }
+
+        impl ::core::convert::TryFrom<u64> for #name {
+            type Error = ();
+
+            fn try_from(value: u64) -> ::core::result::Result<Self, Self::Error> {
+                match value {
+                    #(#value => ::core::result::Result::Ok(#name::#variant),)*
+                    _ => ::core::result::Result::Err(()),
+                }
+            }
+        }
+
+        #(#macros)*
+    })
+}